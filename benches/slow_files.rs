@@ -0,0 +1,129 @@
+//! Concurrent throughput of a static website while some of its files are
+//! slow to read. The slow files are FIFOs nobody writes to yet, so opening
+//! one blocks the way a stalled disk does. Requests for the other files
+//! should go on at the same rate as without them.
+//!
+//! Runs the built server, on Unix as the slow files are made with `mkfifo`:
+//! `cargo bench --bench slow_files`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Requests for fast files sent at once in each iteration.
+const CONCURRENT: usize = 64;
+
+/// Slow files with a request stuck reading each, more than the server has
+/// worker threads.
+const STALLED: usize = 64;
+
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    addr: SocketAddr,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Starts lightron in a directory of its own, serving fast files and FIFOs.
+fn start() -> Server {
+    let dir = std::env::temp_dir().join(format!("lightron-bench-{}", std::process::id()));
+    let site = dir.join("site");
+    std::fs::create_dir_all(&site).unwrap();
+    std::fs::write(site.join("fast.txt"), "lightron ".repeat(512)).unwrap();
+    for i in 0..STALLED {
+        let status = Command::new("mkfifo").arg(site.join(format!("slow{}.txt", i))).status().unwrap();
+        assert!(status.success(), "mkfifo failed");
+    }
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    std::fs::write(dir.join("lightron.conf"), format!(r#"
+[[websites]]
+name = "localhost"
+class = "HTTP"
+access = "Local"
+resource = "{}"
+certificate = ""
+private_key = ""
+port_no = {}
+push_protocol_files = []
+log_level = "Off"
+keep_alive_timeout = 30
+"#, site.display(), port)).unwrap();
+    let child = Command::new(env!("CARGO_BIN_EXE_lightron")).current_dir(&dir).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+    // Killed once dropped, when it doesn't start too.
+    let server = Server { child, dir, addr: SocketAddr::from(([127, 0, 0, 1], port)) };
+    for _ in 0..100 {
+        if std::net::TcpStream::connect(server.addr).is_ok() {
+            return server;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    panic!("lightron didn't start on {}", server.addr);
+}
+
+/// Sends a GET on a connection of its own and reads the whole response.
+async fn get(addr: SocketAddr, path: &str) -> usize {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200 "), "{}", String::from_utf8_lossy(&response));
+    response.len()
+}
+
+async fn fetch_fast(addr: SocketAddr) {
+    let requests: Vec<_> = (0..CONCURRENT).map(|_| tokio::spawn(get(addr, "/fast.txt"))).collect();
+    for request in requests {
+        request.await.unwrap();
+    }
+}
+
+/// Leaves a request waiting on each slow file.
+async fn stall(addr: SocketAddr) -> Vec<TcpStream> {
+    let mut streams = Vec::new();
+    for i in 0..STALLED {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(format!("GET /slow{}.txt HTTP/1.1\r\nHost: localhost\r\n\r\n", i).as_bytes()).await.unwrap();
+        streams.push(stream);
+    }
+    // Gives the server time to be stuck opening them.
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    streams
+}
+
+/// Lets the stuck requests through by opening each FIFO for writing.
+fn release(dir: &Path) {
+    for i in 0..STALLED {
+        let _ = std::fs::OpenOptions::new().write(true).open(dir.join("site").join(format!("slow{}.txt", i)));
+    }
+}
+
+fn slow_files(c: &mut Criterion) {
+    let server = start();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("concurrent fast files");
+    group.throughput(Throughput::Elements(CONCURRENT as u64));
+    group.bench_function("alone", |b| b.to_async(&runtime).iter(|| fetch_fast(server.addr)));
+    let stalled = runtime.block_on(stall(server.addr));
+    group.bench_function(format!("beside {} stalled reads", STALLED), |b| b.to_async(&runtime).iter(|| fetch_fast(server.addr)));
+    group.finish();
+    release(&server.dir);
+    drop(stalled);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20).measurement_time(Duration::from_secs(5));
+    targets = slow_files
+}
+criterion_main!(benches);
@@ -1,5 +1,4 @@
 #![cfg_attr(target_os = "windows", windows_subsystem = "windows")]
-use std::collections::HashMap;
 use serde_derive::{Deserialize,Serialize};
 use std::io::prelude::*;
 use std::convert::TryInto;
@@ -129,7 +128,26 @@ struct Website {
     private_key: PathBuf,
     port_no: u16,
     push_protocol_files: Vec<PathBuf>,
-    log_level: String
+    log_level: String,
+    // Settings the GUI has no fields for, kept as they are.
+    #[serde(flatten)]
+    settings: toml::value::Table
+}
+
+// lightron.conf as the GUI edits it, its upstream groups and any other
+// tables kept as they are.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct Config {
+    #[serde(default)]
+    websites: Vec<Website>,
+    #[serde(flatten)]
+    rest: toml::value::Table
+}
+
+// Goes through `toml::Value` so the tables of each website, such as
+// `[[websites.locations]]`, come out after its plain keys.
+fn conf_to_string(config: &Config) -> String {
+    to_string(&toml::Value::try_from(config).unwrap()).unwrap()
 }
 
 #[derive(Copy, Clone)]
@@ -180,8 +198,8 @@ fn dummy_button(grp:&mut Group, frametype:enums::FrameType, framecolor:enums::Co
 fn print_get_entries(con : String, group : &mut Group) {  
 
     let (width,height) = fltk::app::screen_size();
-    let config: HashMap<String, Vec<Website>> = from_str(&con).unwrap();
-    let websites = config["websites"].clone();
+    let config: Config = from_str(&con).unwrap();
+    let websites = config.websites;
     let mut sr_no = 1;
     let mut counter = 0;
     for website in websites {
@@ -800,17 +818,10 @@ fn main()  -> Result<(), Box<dyn Error>>  {
                 }
                 Message::Remove => {
                     let con=read_conf();
-                    let config: HashMap<String, Vec<Website>> = from_str(&con).unwrap();
-                    let websites: &[Website] = &config["websites"];
-                    let mut v=websites.to_vec();
-                    let mut index = 0;
-                    for website in v.iter() {
-                        if website.name.to_string()==remove_name_input.value() {
-                            break;
-                        }
-                        index += 1;
+                    let mut config: Config = from_str(&con).unwrap();
+                    if let Some(index) = config.websites.iter().position(|website| website.name == remove_name_input.value()) {
+                        config.websites.remove(index);
                     }
-                    v.remove(index);
                     let mut conf_file = if cfg!(target_os = "windows") {
                         std::fs::remove_file("C:\\Program Files\\Common Files\\Lightron\\lightron.conf").unwrap();
                         OpenOptions::new().write(true).create(true).append(true).open("C:\\Program Files\\Common Files\\Lightron\\lightron.conf").unwrap()
@@ -819,10 +830,7 @@ fn main()  -> Result<(), Box<dyn Error>>  {
                         std::fs::remove_file("/etc/lightron.conf").unwrap();
                         OpenOptions::new().write(true).create(true).append(true).open("/etc/lightron.conf").unwrap()
                     };
-                    for website in v.iter() {
-                        let toml = "\n\n[[websites]]\n".to_string()+ &to_string(&website).unwrap();
-                        conf_file.write(toml.as_bytes()).expect("Error writing file.");
-                    }
+                    conf_file.write(conf_to_string(&config).as_bytes()).expect("Error writing file.");
                     frames_group.clear();
                     print_headers(&mut frames_group);
                     let conf_string = read_conf();
@@ -873,9 +881,8 @@ fn main()  -> Result<(), Box<dyn Error>>  {
                     rem_port.hide();
                     remove.hide();
                     let con=read_conf();
-                    let config: HashMap<String, Vec<Website>> = from_str(&con).unwrap();
-                    let websites: &[Website] = &config["websites"];
-                    let v=websites.to_vec();
+                    let config: Config = from_str(&con).unwrap();
+                    let v=config.websites;
                     let mut index = 0;
                     for website in v.iter() {
                         if website.name.to_string()==remove_name_input.value() {
@@ -985,7 +992,7 @@ fn main()  -> Result<(), Box<dyn Error>>  {
                             Vec::new()
                         },
                         log_level : "Info".to_string(),
-
+                        settings : toml::value::Table::new(),
                     };
                     let toml = "[[websites]]\n".to_string()+ &to_string(&config).unwrap();
                     let mut conf_file = if cfg!(target_os = "windows") {
@@ -1015,10 +1022,8 @@ fn main()  -> Result<(), Box<dyn Error>>  {
                 }
                 Message::LogLevelChange => {
                     let con=read_conf();
-                    let config: HashMap<String, Vec<Website>> = from_str(&con).unwrap();
-                    let websites: &[Website] = &config["websites"];
-                    let mut v=websites.to_vec();
-                    for website in v.iter_mut() {
+                    let mut config: Config = from_str(&con).unwrap();
+                    for website in config.websites.iter_mut() {
                         website.log_level = chce.text(chce.value()).unwrap();
                     }
                     let mut conf_file = if cfg!(target_os = "windows") {
@@ -1029,10 +1034,7 @@ fn main()  -> Result<(), Box<dyn Error>>  {
                         std::fs::remove_file("/etc/lightron.conf").unwrap();
                         OpenOptions::new().write(true).create(true).append(true).open("/etc/lightron.conf").unwrap()
                     };
-                    for website in v.iter() {
-                        let toml = "[[websites]]\n".to_string()+ &to_string(&website).unwrap();
-                        conf_file.write(toml.as_bytes()).expect("Error writing file.");
-                    }
+                    conf_file.write(conf_to_string(&config).as_bytes()).expect("Error writing file.");
                 }
             }
             None=>()
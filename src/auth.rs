@@ -0,0 +1,79 @@
+use http::header::{HeaderMap, AUTHORIZATION};
+use crate::Website;
+
+/// Whether the request carries Basic credentials of one of the website's
+/// `auth_users`, which map user names to the bcrypt hash of their password
+/// (`$2y$`, `$2b$` or `$2a$`), as `htpasswd -nB user` prints it. Any other
+/// hash matches no password. Websites without users are open to everyone.
+pub async fn is_authorized(site: &Website, headers: &HeaderMap) -> bool {
+    if site.auth_users.is_empty() {
+        return true;
+    }
+    let credentials = headers.get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic ").or_else(|| value.strip_prefix("basic ")))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok());
+    let (user, password) = match credentials.as_deref().and_then(|credentials| credentials.split_once(':')) {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    let (hash, password) = match site.auth_users.get(user) {
+        Some(hash) => (hash.clone(), password.to_string()),
+        None => return false,
+    };
+    // bcrypt is salted and slow on purpose, and compares in constant time.
+    // Keep it off the runtime's worker threads.
+    tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await.unwrap()
+}
+
+/// Value of the `WWW-Authenticate` header sent with 401 Unauthorized.
+pub fn challenge(site: &Website) -> String {
+    format!("Basic realm=\"{}\", charset=\"UTF-8\"", site.auth_realm.as_deref().unwrap_or("Lightron").replace('"', ""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderValue;
+    use crate::testing;
+
+    // `htpasswd -nbB -C 4 alice secret`
+    const ALICE: &str = "alice = \"$2y$04$UeHoz/T7p/Y2Jt02yz/Vy.6u7gZnfGDp.jiQtJXYn/FKGFPcSyJ7.\"";
+
+    fn basic(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials))).unwrap());
+        headers
+    }
+
+    fn site(users: &str) -> Website {
+        testing::website(&format!("[websites.auth_users]\n{}", users))
+    }
+
+    #[tokio::test]
+    async fn open_without_users() {
+        assert!(is_authorized(&testing::website(""), &HeaderMap::new()).await);
+    }
+
+    #[tokio::test]
+    async fn bcrypt_password_is_accepted() {
+        assert!(is_authorized(&site(ALICE), &basic("alice:secret")).await);
+    }
+
+    #[tokio::test]
+    async fn wrong_password_or_user_is_refused() {
+        let site = site(ALICE);
+        assert!(!is_authorized(&site, &basic("alice:Secret")).await);
+        assert!(!is_authorized(&site, &basic("bob:secret")).await);
+        assert!(!is_authorized(&site, &basic("alice")).await);
+        assert!(!is_authorized(&site, &HeaderMap::new()).await);
+    }
+
+    #[tokio::test]
+    async fn unsalted_hash_matches_nothing() {
+        // The hex SHA-256 of "secret".
+        let site = site("alice = \"2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\"");
+        assert!(!is_authorized(&site, &basic("alice:secret")).await);
+    }
+}
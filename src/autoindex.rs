@@ -0,0 +1,122 @@
+use http::header::{HeaderMap, ACCEPT};
+use httpdate::fmt_http_date;
+use mime_guess::{mime, Mime};
+use serde_derive::Serialize;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use crate::body::Body;
+use crate::{paths, Website};
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    directory: bool,
+    size: u64,
+    modified: Option<String>,
+    #[serde(skip)]
+    modified_secs: u64,
+}
+
+/// Lists the directory `dir`, requested as `uri_path`, as a sortable HTML
+/// table or as JSON for clients asking for `application/json`. Dotfiles are
+/// left out unless the website shows hidden files.
+pub async fn listing(dir: &Path, uri_path: &str, site: &Website, headers: &HeaderMap) -> io::Result<(Mime, Body)> {
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') && !site.autoindex_show_hidden {
+            continue;
+        }
+        let metadata = match entry.metadata().await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        entries.push(Entry {
+            name,
+            directory: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok().map(fmt_http_date),
+            modified_secs: metadata.modified().ok().and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).map(|modified| modified.as_secs()).unwrap_or(0),
+        });
+    }
+    entries.sort_by(|a, b| b.directory.cmp(&a.directory).then_with(|| a.name.cmp(&b.name)));
+    if wants_json(headers) {
+        Ok((mime::APPLICATION_JSON, Body::from_bytes(serde_json::to_vec(&entries).unwrap())))
+    }
+    else {
+        Ok((mime::TEXT_HTML_UTF_8, Body::from_bytes(html(uri_path, &entries))))
+    }
+}
+
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers.get(ACCEPT).and_then(|accept| accept.to_str().ok()).map(|accept| {
+        accept.split(',').any(|media_range| {
+            let mut params = media_range.split(';');
+            params.next().unwrap_or("").trim().eq_ignore_ascii_case("application/json")
+                && !params.any(|param| matches!(param.trim().strip_prefix("q="), Some(q) if q.trim().parse::<f32>().map(|q| q == 0.0).unwrap_or(false)))
+        })
+    }).unwrap_or(false)
+}
+
+fn html(uri_path: &str, entries: &[Entry]) -> String {
+    let title = escape(uri_path);
+    let mut rows = String::new();
+    if uri_path != "/" {
+        rows += "<tr><td data-value=\"\"><a href=\"../\">../</a></td><td data-value=\"-1\"></td><td data-value=\"\"></td></tr>\n";
+    }
+    for entry in entries {
+        let slash = if entry.directory { "/" } else { "" };
+        rows += &format!(
+            "<tr><td data-value=\"{}{}\"><a href=\"./{}{}\">{}{}</a></td><td data-value=\"{}\">{}</td><td data-value=\"{}\">{}</td></tr>\n",
+            if entry.directory { 0 } else { 1 }, escape(&entry.name),
+            escape(&paths::encode(&entry.name)), slash, escape(&entry.name), slash,
+            if entry.directory { -1 } else { entry.size as i64 }, if entry.directory { "-".to_string() } else { entry.size.to_string() },
+            entry.modified_secs,
+            entry.modified.as_deref().unwrap_or("-"),
+        );
+    }
+    format!(r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Index of {title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+th, td {{ padding: 0.25em 1.5em 0.25em 0; text-align: left; }}
+th {{ cursor: pointer; }}
+</style>
+</head>
+<body>
+<h1>Index of {title}</h1>
+<table>
+<thead><tr><th>Name</th><th>Size</th><th>Last modified</th></tr></thead>
+<tbody>
+{rows}</tbody>
+</table>
+<script>
+document.querySelectorAll("th").forEach(function (th, column) {{
+    var ascending = true;
+    th.addEventListener("click", function () {{
+        var tbody = document.querySelector("tbody");
+        var rows = Array.prototype.slice.call(tbody.rows).filter(function (row) {{ return row.cells[0].textContent !== "../"; }});
+        rows.sort(function (a, b) {{
+            var x = a.cells[column].dataset.value, y = b.cells[column].dataset.value;
+            var order = column === 0 ? x.localeCompare(y) : Number(x) - Number(y);
+            return ascending ? order : -order;
+        }});
+        ascending = !ascending;
+        rows.forEach(function (row) {{ tbody.appendChild(row); }});
+    }});
+}});
+</script>
+</body>
+</html>
+"#, title = title, rows = rows)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
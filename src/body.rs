@@ -0,0 +1,125 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use crate::proxy::UpstreamBody;
+
+/// Largest chunk read from a file at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A piece of a response body, either already in memory or `len` bytes of the
+/// body's file starting at `offset`.
+pub enum Part {
+    Bytes(Bytes),
+    File(u64, u64),
+}
+
+/// Response body that is read and sent chunk by chunk, so a file never has to
+/// be held in memory as a whole.
+pub struct Body {
+    file: Option<File>,
+    parts: VecDeque<Part>,
+    encoded: Option<Pin<Box<dyn AsyncRead + Send>>>,
+    upstream: Option<Box<UpstreamBody>>,
+}
+
+impl Body {
+    pub fn empty() -> Body {
+        Body { file: None, parts: VecDeque::new(), encoded: None, upstream: None }
+    }
+
+    pub fn from_bytes<B: Into<Bytes>>(bytes: B) -> Body {
+        let mut body = Body::empty();
+        body.parts.push_back(Part::Bytes(bytes.into()));
+        body
+    }
+
+    pub fn from_file(file: File, len: u64) -> Body {
+        let mut parts = VecDeque::new();
+        parts.push_back(Part::File(0, len));
+        Body { file: Some(file), parts, encoded: None, upstream: None }
+    }
+
+    /// A response body streamed from the upstream of a proxied website.
+    pub fn from_upstream(upstream: UpstreamBody) -> Body {
+        Body { file: None, parts: VecDeque::new(), encoded: None, upstream: Some(Box::new(upstream)) }
+    }
+
+    /// Replaces the parts of the body, keeping its file so `Part::File` can
+    /// point at any range of it.
+    pub fn with_parts(self, parts: Vec<Part>) -> Body {
+        Body { file: self.file, parts: parts.into(), encoded: None, upstream: None }
+    }
+
+    /// Sends the whole file of the body through `encoder` instead, e.g. to
+    /// compress it on the fly. Bodies without a file are left as they are.
+    pub fn encode<F>(self, encoder: F) -> Body
+    where
+        F: FnOnce(BufReader<File>) -> Pin<Box<dyn AsyncRead + Send>>,
+    {
+        match self.file {
+            Some(file) => Body { file: None, parts: VecDeque::new(), encoded: Some(encoder(BufReader::new(file))), upstream: None },
+            None => self,
+        }
+    }
+
+    /// Number of bytes left to send, `None` for encoded bodies and upstream
+    /// bodies without a length, whose size is only known once all of it was
+    /// read.
+    pub fn len(&self) -> Option<u64> {
+        if let Some(upstream) = self.upstream.as_ref() {
+            return upstream.len();
+        }
+        if self.encoded.is_some() {
+            return None;
+        }
+        Some(self.parts.iter().map(|part| match part {
+            Part::Bytes(bytes) => bytes.len() as u64,
+            Part::File(_, len) => *len,
+        }).sum())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    /// Returns the next chunk of the body, or `None` once all of it was read.
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if let Some(upstream) = self.upstream.as_mut() {
+            return upstream.next_chunk().await;
+        }
+        if let Some(encoded) = self.encoded.as_mut() {
+            let mut chunk = BytesMut::with_capacity(CHUNK_SIZE);
+            return match encoded.read_buf(&mut chunk).await? {
+                0 => Ok(None),
+                _ => Ok(Some(chunk.freeze())),
+            };
+        }
+        loop {
+            match self.parts.pop_front() {
+                Some(Part::Bytes(bytes)) if bytes.is_empty() => continue,
+                Some(Part::Bytes(bytes)) => return Ok(Some(bytes)),
+                Some(Part::File(_, 0)) => continue,
+                Some(Part::File(offset, len)) => {
+                    let file = self.file.as_mut().ok_or_else(|| io::Error::other("body has no file"))?;
+                    file.seek(SeekFrom::Start(offset)).await?;
+                    let mut chunk = BytesMut::with_capacity(CHUNK_SIZE.min(len as usize));
+                    while (chunk.len() as u64) < len.min(CHUNK_SIZE as u64) {
+                        if file.read_buf(&mut chunk).await? == 0 {
+                            return Err(io::ErrorKind::UnexpectedEof.into());
+                        }
+                    }
+                    chunk.truncate(len.min(CHUNK_SIZE as u64) as usize);
+                    let read = chunk.len() as u64;
+                    if read < len {
+                        self.parts.push_front(Part::File(offset + read, len - read));
+                    }
+                    return Ok(Some(chunk.freeze()));
+                },
+                None => return Ok(None),
+            }
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use http::header::{HeaderMap, ACCEPT_ENCODING, RANGE};
+use mime_guess::{mime, Mime};
+use crate::body::Body;
+use crate::Website;
+
+/// Content codings Lightron compresses responses with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+/// Codings in order of preference, for when the client weighs them equally.
+const PREFERENCE: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+impl Encoding {
+    /// Name of the coding in `Accept-Encoding` and `Content-Encoding`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Extension of a file precompressed with the coding, e.g. `app.js.br`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+/// Text formats shrink well, already compressed images, video and archives do
+/// not.
+pub fn is_compressible(content_type: &Mime) -> bool {
+    content_type.type_() == mime::TEXT
+        || matches!(content_type.subtype().as_str(), "javascript" | "x-javascript" | "ecmascript" | "json" | "xml" | "wasm" | "x-font-ttf" | "vnd.ms-fontobject")
+        || matches!(content_type.suffix(), Some(suffix) if suffix == mime::XML || suffix == mime::JSON)
+}
+
+/// Whether the representation sent for `content_type` depends on the
+/// client's `Accept-Encoding`, which caches have to be told with `Vary`.
+pub fn varies(site: &Website, content_type: &Mime) -> bool {
+    site.compression && is_compressible(content_type)
+}
+
+/// Picks the coding for a file of `len` bytes from the client's
+/// `Accept-Encoding`, `None` meaning the file is sent as it is. Range requests
+/// are always answered from the file itself.
+pub fn negotiate(headers: &HeaderMap, site: &Website, content_type: &Mime, len: u64) -> Option<Encoding> {
+    if !varies(site, content_type) || len < site.compression_min_size || headers.contains_key(RANGE) {
+        return None;
+    }
+    accepted(headers).first().copied()
+}
+
+/// The codings the client accepts, best first.
+pub fn accepted(headers: &HeaderMap) -> Vec<Encoding> {
+    let accept_encoding = match headers.get(ACCEPT_ENCODING).and_then(|accept_encoding| accept_encoding.to_str().ok()) {
+        Some(accept_encoding) => accept_encoding,
+        None => return Vec::new(),
+    };
+    let mut wildcard = None;
+    let mut weights: Vec<(String, f32)> = Vec::new();
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let weight = params.filter_map(|param| param.trim().strip_prefix("q=")).find_map(|q| q.trim().parse::<f32>().ok()).unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(weight);
+        }
+        else if name == "x-gzip" {
+            weights.push(("gzip".to_string(), weight));
+        }
+        else {
+            weights.push((name, weight));
+        }
+    }
+    let mut accepted: Vec<(Encoding, f32)> = PREFERENCE.iter().copied().filter_map(|encoding| {
+        let weight = weights.iter().find(|(name, _)| name == encoding.as_str()).map(|(_, weight)| *weight).or(wildcard).unwrap_or(0.0);
+        if weight > 0.0 { Some((encoding, weight)) } else { None }
+    }).collect();
+    // A stable sort keeps equally weighted codings in order of preference.
+    accepted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    accepted.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// Each coding of a file is a representation of its own and needs its own
+/// entity tag.
+pub fn etag(etag: &str, encoding: Encoding) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), encoding.as_str())
+}
+
+/// Compresses the file of `body` on the fly with `encoding`.
+pub fn encode(body: Body, encoding: Encoding, level: Option<i32>) -> Body {
+    let level = level.map(Level::Precise).unwrap_or(Level::Default);
+    body.encode(|file| match encoding {
+        Encoding::Brotli => Box::pin(BrotliEncoder::with_quality(file, level)),
+        Encoding::Zstd => Box::pin(ZstdEncoder::with_quality(file, level)),
+        Encoding::Gzip => Box::pin(GzipEncoder::with_quality(file, level)),
+    })
+}
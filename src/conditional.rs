@@ -0,0 +1,127 @@
+use http::header::{HeaderMap, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_UNMODIFIED_SINCE};
+use http::StatusCode;
+use httpdate::parse_http_date;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Builds the entity tag of a file from its size and modification time. Files
+/// changed within the last second could still change again without moving the
+/// timestamp, so their tag is only weak.
+pub fn etag(len: u64, last_modified: Option<SystemTime>) -> String {
+    let modified = last_modified.and_then(|modified| modified.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    let recent = last_modified.map(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() < Duration::from_secs(1)).unwrap_or(true);
+    format!("{}\"{:x}.{:x}-{:x}\"", if recent { "W/" } else { "" }, modified.as_secs(), modified.subsec_nanos(), len)
+}
+
+/// Evaluates the preconditions of a GET or HEAD request against the current
+/// validators of the file, in the order of RFC 7232 section 6. Returns the
+/// status to answer with instead of the file, if any.
+pub fn evaluate(headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> Option<StatusCode> {
+    if let Some(if_match) = header(headers, IF_MATCH) {
+        if !matches(if_match, etag, strong_eq) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+    else if let (Some(date), Some(modified)) = (header(headers, IF_UNMODIFIED_SINCE).and_then(|date| parse_http_date(date).ok()), last_modified) {
+        if truncate(modified) > date {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+    if let Some(if_none_match) = header(headers, IF_NONE_MATCH) {
+        if matches(if_none_match, etag, weak_eq) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+    else if let (Some(date), Some(modified)) = (header(headers, IF_MODIFIED_SINCE).and_then(|date| parse_http_date(date).ok()), last_modified) {
+        if truncate(modified) <= date {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+    None
+}
+
+/// Strong comparison, as required for `If-Match` and `If-Range`: weak tags
+/// never match.
+pub fn strong_eq(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn matches(list: &str, etag: &str, eq: fn(&str, &str) -> bool) -> bool {
+    list.trim() == "*" || list.split(',').map(|tag| tag.trim()).any(|tag| eq(tag, etag))
+}
+
+fn header(headers: &HeaderMap, name: http::header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// HTTP dates only carry whole seconds.
+fn truncate(time: SystemTime) -> SystemTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpdate::fmt_http_date;
+
+    const ETAG: &str = "\"5f.0-a\"";
+    const WEAK_ETAG: &str = "W/\"5f.0-a\"";
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+    }
+
+    /// Dates `offset` seconds from the file's modification time.
+    fn date(offset: i64) -> String {
+        fmt_http_date(UNIX_EPOCH + Duration::from_secs((1_600_000_000 + offset) as u64))
+    }
+
+    fn status(conditions: &[(http::header::HeaderName, &str)], etag: &str) -> Option<StatusCode> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in conditions {
+            headers.insert(name.clone(), value.parse().unwrap());
+        }
+        evaluate(&headers, etag, Some(modified()))
+    }
+
+    #[test]
+    fn if_match_takes_precedence_over_if_unmodified_since() {
+        assert_eq!(status(&[(IF_MATCH, ETAG), (IF_UNMODIFIED_SINCE, &date(-10))], ETAG), None);
+        assert_eq!(status(&[(IF_MATCH, "\"other\""), (IF_UNMODIFIED_SINCE, &date(10))], ETAG), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(status(&[(IF_UNMODIFIED_SINCE, &date(-10))], ETAG), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(status(&[(IF_UNMODIFIED_SINCE, &date(0))], ETAG), None);
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence_over_if_modified_since() {
+        assert_eq!(status(&[(IF_NONE_MATCH, "\"other\""), (IF_MODIFIED_SINCE, &date(10))], ETAG), None);
+        assert_eq!(status(&[(IF_NONE_MATCH, ETAG), (IF_MODIFIED_SINCE, &date(-10))], ETAG), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(status(&[(IF_MODIFIED_SINCE, &date(0))], ETAG), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(status(&[(IF_MODIFIED_SINCE, &date(-1))], ETAG), None);
+    }
+
+    #[test]
+    fn failed_if_match_wins_over_if_none_match() {
+        assert_eq!(status(&[(IF_MATCH, "\"other\""), (IF_NONE_MATCH, ETAG)], ETAG), Some(StatusCode::PRECONDITION_FAILED));
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        assert_eq!(status(&[(IF_MATCH, WEAK_ETAG)], ETAG), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(status(&[(IF_MATCH, ETAG)], WEAK_ETAG), Some(StatusCode::PRECONDITION_FAILED));
+        assert_eq!(status(&[(IF_MATCH, &format!("\"other\", {}", ETAG))], ETAG), None);
+        assert_eq!(status(&[(IF_MATCH, "*")], WEAK_ETAG), None);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        assert_eq!(status(&[(IF_NONE_MATCH, WEAK_ETAG)], ETAG), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(status(&[(IF_NONE_MATCH, ETAG)], WEAK_ETAG), Some(StatusCode::NOT_MODIFIED));
+        assert_eq!(status(&[(IF_NONE_MATCH, "\"other\", W/\"another\"")], ETAG), None);
+        assert_eq!(status(&[(IF_NONE_MATCH, "*")], ETAG), Some(StatusCode::NOT_MODIFIED));
+    }
+}
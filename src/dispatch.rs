@@ -0,0 +1,207 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use bytes::{Bytes,BytesMut};
+use http::{Method,Request,Response,StatusCode,Uri};
+use http::header::{CONTENT_LENGTH,HOST};
+use http::response::Builder;
+use httpdate::fmt_http_date;
+use log::{warn,debug};
+use crate::{DomainMap,Website};
+use crate::auth;
+use crate::body::Body;
+use crate::error_pages;
+use crate::locations;
+use crate::methods;
+use crate::paths;
+use crate::proxy;
+use crate::rewrites::{self,Rewritten};
+use crate::web_docs;
+
+/// Answers a request the same way whichever protocol it came in on: finds
+/// the website for its host among those of the port, turns away paths that
+/// don't normalize, applies the rewrites and location of that website,
+/// checks credentials and method, then forwards it to the upstreams or
+/// serves the website's files. Answers the website the response is for
+/// along with the response.
+///
+/// `read_body` reads the request body, up to the size it's given, and is
+/// only called for proxied websites. The body of any other request is left
+/// to the caller.
+pub async fn dispatch<'a, F, R>(website: &'a Website, domain_map: Option<&'a DomainMap>, request: &Request<()>, peer_addr: SocketAddr, scheme: &str, read_body: F) -> io::Result<(&'a Website, Builder, Body)>
+where
+    F: FnOnce(u64) -> R,
+    R: Future<Output = io::Result<Result<Bytes,StatusCode>>>,
+{
+    let port_no = website.port_no;
+    let host = host(request);
+    let site = match web_docs::site_for(website, domain_map, host) {
+        Some(site) => site,
+        None => {
+            warn!("{} 400 Unknown host {:?}",port_no,host);
+            let (response,contents) = error_pages::response(website, StatusCode::BAD_REQUEST).await;
+            return Ok((website, response, contents));
+        }
+    };
+    // Everything after goes by the normalized path, a path that can't be
+    // normalized would slip past locations and reach upstreams as it is.
+    if let Err(status) = paths::normalize(request.uri().path()) {
+        if status == StatusCode::FORBIDDEN {
+            warn!("{} SECURITY 403 {:?} from {}",port_no,request.uri().path(),peer_addr);
+        }
+        else {
+            warn!("{} {} {}",port_no,status.as_str(),request.uri().path());
+        }
+        let (response,contents) = error_pages::response(site, status).await;
+        return Ok((site, response, contents));
+    }
+    let rewritten;
+    let request = match rewrites::apply(site, request.uri(), host, scheme) {
+        Rewritten::Unchanged => request,
+        Rewritten::Internal(uri) => {
+            rewritten = with_uri(request, uri);
+            &rewritten
+        },
+        Rewritten::Redirect(status, location) => {
+            let response = Response::builder().status(status).header("Date", fmt_http_date(SystemTime::now())).header("Location", location).header("Content-Length", 0);
+            return Ok((site, response, Body::empty()));
+        },
+    };
+    let site = locations::select(site, request.uri().path());
+    if !auth::is_authorized(site, request.headers()).await {
+        warn!("{} 401 {} from {}",port_no,request.uri().path(),peer_addr);
+        let (response,contents) = error_pages::response(site, StatusCode::UNAUTHORIZED).await;
+        return Ok((site, response.header("WWW-Authenticate", auth::challenge(site)), contents));
+    }
+    if !methods::is_allowed(site, request.method()) {
+        warn!("{} 405 {} Not Allowed",port_no,request.method());
+        let (response,contents) = error_pages::response(site, StatusCode::METHOD_NOT_ALLOWED).await;
+        return Ok((site, response.header("Allow", methods::allow(site)), contents));
+    }
+    if proxy::is_proxied(site) {
+        let body = match read_body(site.max_body_size).await? {
+            Ok(body) => body,
+            Err(status) => {
+                warn!("{} {} Request body",port_no,status.as_str());
+                let (response,contents) = error_pages::response(site, status).await;
+                return Ok((site, response, contents));
+            }
+        };
+        let (response,contents) = match proxy::forward(site, request, body, peer_addr, scheme).await {
+            Ok(response) => response,
+            Err(status) => error_pages::response(site, status).await,
+        };
+        return Ok((site, response, contents));
+    }
+    if request.method() == Method::OPTIONS {
+        let response = Response::builder().status(StatusCode::NO_CONTENT).header("Date", fmt_http_date(SystemTime::now())).header("Allow", methods::allow(site));
+        return Ok((site, response, Body::empty()));
+    }
+    let content_length = request.headers().get(CONTENT_LENGTH).and_then(|len| len.to_str().ok()).and_then(|len| len.parse::<u64>().ok()).unwrap_or(0);
+    if content_length > site.max_body_size {
+        warn!("{} 413 Request body of {} bytes",port_no,content_length);
+        let (response,contents) = error_pages::response(site, StatusCode::PAYLOAD_TOO_LARGE).await;
+        return Ok((site, response, contents));
+    }
+    debug!("{} path : {}",port_no,request.uri().path());
+    let (response,contents) = web_docs::serve(site, request.uri(), request.headers(), peer_addr).await;
+    Ok((site, response, contents))
+}
+
+/// The host a request is for, from its URI or else its `Host` header.
+pub fn host(request: &Request<()>) -> Option<&str> {
+    request.uri().host().or_else(|| request.headers().get(HOST).and_then(|host| host.to_str().ok()))
+}
+
+/// A request body that comes in frames, as it does over HTTP/2 and HTTP/3.
+pub trait RequestBody {
+    /// The data of the next frame, `None` once the body ended.
+    async fn next_data(&mut self) -> io::Result<Option<Bytes>>;
+}
+
+/// Reads the body of a request for a proxied website, the error being the
+/// status to answer with when it's larger than `limit`.
+pub async fn read_body<B: RequestBody>(mut body: B, limit: u64) -> io::Result<Result<Bytes,StatusCode>> {
+    let mut read = BytesMut::new();
+    while let Some(data) = body.next_data().await? {
+        if read.len() as u64 + data.len() as u64 > limit {
+            return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+        read.extend_from_slice(&data);
+    }
+    Ok(Ok(read.freeze()))
+}
+
+/// The request with the URI an internal rewrite gave it.
+fn with_uri(request: &Request<()>, uri: Uri) -> Request<()> {
+    let mut rewritten = Request::new(());
+    *rewritten.method_mut() = request.method().clone();
+    *rewritten.uri_mut() = uri;
+    *rewritten.version_mut() = request.version();
+    *rewritten.headers_mut() = request.headers().clone();
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// An upstream answering 200 to anything, passing on the request line
+    /// of each request it gets.
+    async fn upstream() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (seen, requests) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut head = vec![0; 4096];
+                let len = stream.read(&mut head).await.unwrap();
+                let _ = seen.send(String::from_utf8_lossy(&head[..len]).lines().next().unwrap_or("").to_string());
+                stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await.unwrap();
+            }
+        });
+        (addr, requests)
+    }
+
+    async fn status(site: &Website, path: &str) -> StatusCode {
+        let request = Request::get(path).header(HOST, "localhost").body(()).unwrap();
+        let peer_addr = "127.0.0.1:40000".parse().unwrap();
+        let (_, response, _) = dispatch(site, None, &request, peer_addr, "http", |_| async { Ok(Ok(Bytes::new())) }).await.unwrap();
+        response.body(()).unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn protected_location_of_a_proxied_site_is_not_bypassed() {
+        let (addr, mut requests) = upstream().await;
+        let site = testing::website(&format!("mode = \"Proxy\"\nproxy_pass = [{:?}]\n[[websites.locations]]\nprefix = \"/admin/\"\n[websites.locations.auth_users]\nalice = \"$2y$04$UeHoz/T7p/Y2Jt02yz/Vy.6u7gZnfGDp.jiQtJXYn/FKGFPcSyJ7.\"\n", addr));
+        assert_eq!(status(&site, "/admin/secret").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&site, "/x/../admin/secret").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&site, "/x/../../admin/secret").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&site, "/admin/%ff/../secret").await, StatusCode::BAD_REQUEST);
+        assert_eq!(status(&site, "/admin/%c0%ae%c0%ae/secret").await, StatusCode::FORBIDDEN);
+        assert_eq!(status(&site, "/public").await, StatusCode::OK);
+        assert_eq!(requests.recv().await.unwrap(), "GET /public HTTP/1.1");
+        assert!(requests.try_recv().is_err());
+    }
+
+    /// Frames of a request body, in the order they come in.
+    struct Frames(Vec<&'static str>);
+
+    impl RequestBody for Frames {
+        async fn next_data(&mut self) -> io::Result<Option<Bytes>> {
+            Ok((!self.0.is_empty()).then(|| Bytes::from_static(self.0.remove(0).as_bytes())))
+        }
+    }
+
+    #[tokio::test]
+    async fn framed_body_is_read_up_to_the_limit() {
+        assert_eq!(read_body(Frames(vec!["ab", "", "cd"]), 4).await.unwrap(), Ok(Bytes::from_static(b"abcd")));
+        assert_eq!(read_body(Frames(vec![]), 0).await.unwrap(), Ok(Bytes::new()));
+        assert_eq!(read_body(Frames(vec!["ab", "cde"]), 4).await.unwrap(), Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+}
@@ -0,0 +1,43 @@
+use bytes::Bytes;
+use http::response::Builder;
+use http::{Response, StatusCode};
+use httpdate::fmt_http_date;
+use log::warn;
+use std::time::SystemTime;
+use crate::body::Body;
+use crate::web_docs::{self, Resolved};
+use crate::Website;
+
+/// Pages Lightron ships with, used when a website configures none of its own.
+const NOT_FOUND: &[u8] = include_bytes!("../assets/404.html");
+const FORBIDDEN: &[u8] = include_bytes!("../assets/403.html");
+
+/// Starts the response for an error `status`, with the website's page for it
+/// as the body.
+pub async fn response(site: &Website, status: StatusCode) -> (Builder, Body) {
+    let body = page(site, status).await;
+    (Response::builder().status(status).header("Date", fmt_http_date(SystemTime::now())).header("Content-Type", "text/html; charset=utf-8").header("Content-Length", body.len().unwrap_or(0)), body)
+}
+
+/// Reads the page `error_pages` names for `status`, resolved under the
+/// website's resource like any other file. A page that can't be read falls
+/// back to the built-in one rather than failing the response.
+async fn page(site: &Website, status: StatusCode) -> Body {
+    if let Some(page) = site.error_pages.get(status.as_str()) {
+        match web_docs::resolve(&site.resource, page).await {
+            Resolved::Found(path) => match tokio::fs::read(&path).await {
+                Ok(contents) => return Body::from_bytes(contents),
+                Err(err) => warn!("{} error page {} : {}", site.port_no, path.display(), err),
+            },
+            _ => warn!("{} error page {} not found in {}", site.port_no, page, site.resource),
+        }
+    }
+    match status {
+        StatusCode::NOT_FOUND => Body::from_bytes(Bytes::from_static(NOT_FOUND)),
+        StatusCode::FORBIDDEN => Body::from_bytes(Bytes::from_static(FORBIDDEN)),
+        _ => {
+            let title = format!("{} {}", status.as_str(), status.canonical_reason().unwrap_or(""));
+            Body::from_bytes(format!("<!DOCTYPE html>\n<html>\n<head><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<hr>\n<p>Lightron/0.1.0</p>\n</body>\n</html>\n", title.trim_end()))
+        },
+    }
+}
@@ -0,0 +1,290 @@
+use bytes::{Buf, Bytes, BytesMut};
+use http::header::{CONTENT_LENGTH, HOST, TRANSFER_ENCODING, UPGRADE};
+use http::{Request, Version};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+/// What an HTTP/2 client sends first on a connection.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Length of an HTTP/2 frame header.
+const FRAME_HEADER_SIZE: usize = 9;
+
+/// Largest frame a peer has to accept before settings say otherwise.
+const MAX_FRAME_SIZE: usize = 16384;
+
+/// Headers of an upgraded request that only concern the HTTP/1.1 connection.
+const CONNECTION_HEADERS: [&str; 8] = ["connection", "upgrade", "http2-settings", "host", "keep-alive", "proxy-connection", "transfer-encoding", "te"];
+
+/// A connection that reads `prefix` before what is left on `stream`, so the
+/// h2 server sees bytes already read while looking for HTTP/2.
+pub struct Rewind<S> {
+    prefix: Bytes,
+    stream: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..len]);
+            self.prefix.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Whether the connection starts with the HTTP/2 preface, for clients with
+/// prior knowledge. What was read stays in `buffer` either way.
+pub async fn has_preface<S: AsyncRead + Unpin>(stream: &mut S, buffer: &mut BytesMut, keep_alive_timeout: Duration) -> io::Result<bool> {
+    loop {
+        let len = buffer.len().min(PREFACE.len());
+        if buffer[..len] != PREFACE[..len] {
+            return Ok(false);
+        }
+        if len == PREFACE.len() {
+            return Ok(true);
+        }
+        // A connection that closes or idles is left to the HTTP/1.1 side.
+        match timeout(keep_alive_timeout, stream.read_buf(buffer)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(false),
+            Ok(Ok(_)) => {},
+            Ok(Err(err)) => return Err(err),
+        }
+    }
+}
+
+/// Hands a connection that started with the preface to the h2 server.
+pub fn prior_knowledge<S>(stream: S, buffer: BytesMut) -> Rewind<S> {
+    Rewind { prefix: buffer.freeze(), stream }
+}
+
+/// What a request asking to upgrade to h2c turns into on the HTTP/2 side.
+pub struct Upgrade {
+    /// Payload of the SETTINGS frame its `HTTP2-Settings` header carries.
+    settings: Bytes,
+    /// The request as the HEADERS frame of stream 1.
+    headers: Bytes,
+}
+
+/// The upgrade a request asks for, when it asks to upgrade to h2c and can
+/// be. Requests with a body, or without exactly one valid `HTTP2-Settings`,
+/// stay on HTTP/1.1.
+pub fn upgrade_of(request: &Request<()>) -> Option<Upgrade> {
+    let upgrade = request.headers().get(UPGRADE).and_then(|upgrade| upgrade.to_str().ok())?;
+    if request.version() != Version::HTTP_11
+        || !upgrade.split(',').any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"))
+        || request.headers().contains_key(TRANSFER_ENCODING)
+        || request.headers().get(CONTENT_LENGTH).map(|len| len != "0").unwrap_or(false) {
+        return None;
+    }
+    let settings = settings(request)?;
+    let path = request.uri().path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or("/");
+    // Literals that aren't indexed, the client's header table stays as its
+    // encoder knows it.
+    let mut block = Vec::new();
+    push_literal(&mut block, b":method", request.method().as_str().as_bytes());
+    push_literal(&mut block, b":scheme", b"http");
+    if let Some(host) = request.headers().get(HOST) {
+        push_literal(&mut block, b":authority", host.as_bytes());
+    }
+    push_literal(&mut block, b":path", path.as_bytes());
+    for (name, value) in request.headers() {
+        if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            push_literal(&mut block, name.as_str().as_bytes(), value.as_bytes());
+        }
+    }
+    if block.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + block.len());
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+    // HEADERS with END_STREAM and END_HEADERS, on stream 1.
+    frame.extend_from_slice(&[0x1, 0x5]);
+    frame.extend_from_slice(&1u32.to_be_bytes());
+    frame.extend_from_slice(&block);
+    Some(Upgrade { settings, headers: Bytes::from(frame) })
+}
+
+/// The settings of the `HTTP2-Settings` header, a SETTINGS frame payload in
+/// base64url without padding (RFC 7540 §3.2.1), if there is one header and
+/// its settings are valid.
+fn settings(request: &Request<()>) -> Option<Bytes> {
+    let mut values = request.headers().get_all("http2-settings").iter();
+    let value = values.next()?.to_str().ok()?.trim();
+    if values.next().is_some() {
+        return None;
+    }
+    let payload = base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()?;
+    if payload.len() % 6 != 0 || payload.len() > MAX_FRAME_SIZE {
+        return None;
+    }
+    for setting in payload.chunks(6) {
+        let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+        let valid = match u16::from_be_bytes([setting[0], setting[1]]) {
+            // SETTINGS_ENABLE_PUSH
+            0x2 => value <= 1,
+            // SETTINGS_INITIAL_WINDOW_SIZE
+            0x4 => value <= 0x7fff_ffff,
+            // SETTINGS_MAX_FRAME_SIZE
+            0x5 => (MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value),
+            // Others, known or not, take any value.
+            _ => true,
+        };
+        if !valid {
+            return None;
+        }
+    }
+    Some(Bytes::from(payload))
+}
+
+/// Switches a connection to h2c after a request asked for `upgrade`. The
+/// client's preface and first SETTINGS are read so the request can be
+/// slipped in after them, as stream 1 the response is sent on. The settings
+/// of its `HTTP2-Settings` go in front of that first SETTINGS, which is
+/// acknowledged once for both as the client expects.
+pub async fn upgrade<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, mut buffer: BytesMut, upgrade: Upgrade, keep_alive_timeout: Duration) -> io::Result<Rewind<S>> {
+    stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n").await?;
+    stream.flush().await?;
+    let settings_len = loop {
+        if buffer.len() >= PREFACE.len() + FRAME_HEADER_SIZE {
+            // A SETTINGS frame, not an acknowledgement.
+            if &buffer[..PREFACE.len()] != PREFACE || buffer[PREFACE.len() + 3] != 0x4 || buffer[PREFACE.len() + 4] & 0x1 != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "no HTTP/2 preface after upgrade"));
+            }
+            let len = u32::from_be_bytes([0, buffer[PREFACE.len()], buffer[PREFACE.len() + 1], buffer[PREFACE.len() + 2]]) as usize;
+            if len + upgrade.settings.len() > MAX_FRAME_SIZE {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "HTTP/2 settings too large"));
+            }
+            if buffer.len() >= PREFACE.len() + FRAME_HEADER_SIZE + len {
+                break len;
+            }
+        }
+        match timeout(keep_alive_timeout, stream.read_buf(&mut buffer)).await {
+            Ok(Ok(0)) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(Ok(_)) => {},
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        }
+    };
+    let settings_start = PREFACE.len() + FRAME_HEADER_SIZE;
+    let settings_end = settings_start + settings_len;
+    let mut prefix = BytesMut::with_capacity(buffer.len() + upgrade.settings.len() + upgrade.headers.len());
+    prefix.extend_from_slice(PREFACE);
+    prefix.extend_from_slice(&((settings_len + upgrade.settings.len()) as u32).to_be_bytes()[1..]);
+    prefix.extend_from_slice(&buffer[PREFACE.len() + 3..settings_start]);
+    prefix.extend_from_slice(&upgrade.settings);
+    prefix.extend_from_slice(&buffer[settings_start..settings_end]);
+    prefix.extend_from_slice(&upgrade.headers);
+    prefix.extend_from_slice(&buffer[settings_end..]);
+    Ok(Rewind { prefix: prefix.freeze(), stream })
+}
+
+/// An HPACK literal header field without indexing, its name a literal too.
+fn push_literal(block: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    block.push(0x00);
+    push_string(block, name);
+    push_string(block, value);
+}
+
+fn push_string(block: &mut Vec<u8>, string: &[u8]) {
+    // Not Huffman coded, a length with a 7 bit prefix.
+    if string.len() < 0x7f {
+        block.push(string.len() as u8);
+    }
+    else {
+        block.push(0x7f);
+        let mut left = string.len() - 0x7f;
+        while left >= 0x80 {
+            block.push((left & 0x7f) as u8 | 0x80);
+            left >>= 7;
+        }
+        block.push(left as u8);
+    }
+    block.extend_from_slice(string);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// MAX_CONCURRENT_STREAMS 100, INITIAL_WINDOW_SIZE 32 MiB, ENABLE_PUSH 0.
+    const SETTINGS: &str = "AAMAAABkAAQCAAAAAAIAAAAA";
+
+    fn request(settings: &[&str]) -> Request<()> {
+        let mut request = Request::builder().uri("/").header(HOST, "localhost").header("connection", "Upgrade, HTTP2-Settings").header(UPGRADE, "h2c");
+        for value in settings {
+            request = request.header("http2-settings", *value);
+        }
+        request.body(()).unwrap()
+    }
+
+    #[test]
+    fn settings_header_is_decoded() {
+        let upgrade = upgrade_of(&request(&[SETTINGS])).unwrap();
+        assert_eq!(&upgrade.settings[..], &[0, 3, 0, 0, 0, 100, 0, 4, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+        // HEADERS of stream 1.
+        assert_eq!(&upgrade.headers[3..9], &[0x1, 0x5, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn missing_or_repeated_settings_stay_on_http1() {
+        assert!(upgrade_of(&request(&[])).is_none());
+        assert!(upgrade_of(&request(&[SETTINGS, SETTINGS])).is_none());
+    }
+
+    #[test]
+    fn invalid_settings_stay_on_http1() {
+        // Not base64url, not whole settings, ENABLE_PUSH of 2.
+        for settings in ["AAMA+/AA", "AAMAAAA", "AAIAAAAC"] {
+            assert!(upgrade_of(&request(&[settings])).is_none(), "{}", settings);
+        }
+        // No settings at all is valid.
+        assert!(upgrade_of(&request(&[""])).is_some());
+    }
+
+    #[tokio::test]
+    async fn settings_go_in_front_of_the_first_settings_frame() {
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let requested = upgrade_of(&request(&[SETTINGS])).unwrap();
+        let headers = requested.headers.clone();
+        let mut sent = PREFACE.to_vec();
+        // SETTINGS with MAX_FRAME_SIZE 20000, then a PING.
+        sent.extend_from_slice(&[0, 0, 6, 0x4, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0x4e, 0x20]);
+        sent.extend_from_slice(&[0, 0, 8, 0x6, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        client.write_all(&sent).await.unwrap();
+        let mut stream = upgrade(server, BytesMut::new(), requested, Duration::from_secs(5)).await.unwrap();
+
+        let mut switching = vec![0; 71];
+        client.read_exact(&mut switching).await.unwrap();
+        assert!(switching.starts_with(b"HTTP/1.1 101 "));
+        let mut expected = PREFACE.to_vec();
+        expected.extend_from_slice(&[0, 0, 24, 0x4, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 3, 0, 0, 0, 100, 0, 4, 2, 0, 0, 0, 0, 2, 0, 0, 0, 0]);
+        expected.extend_from_slice(&[0, 5, 0, 0, 0x4e, 0x20]);
+        expected.extend_from_slice(&headers);
+        expected.extend_from_slice(&sent[PREFACE.len() + 15..]);
+        let mut received = vec![0; expected.len()];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+    }
+}
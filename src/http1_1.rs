@@ -1,32 +1,41 @@
 use std::io;
 use tokio::net::TcpListener;
-use tokio::net::TcpStream;
-use std::fs::File;
-use http::{StatusCode};
+use http::{Method,Request,Response,StatusCode,Version};
+use http::response::Builder;
+use http::header::{CONNECTION,CONTENT_LENGTH,EXPECT,TRANSFER_ENCODING};
+use bytes::{Buf,Bytes,BytesMut};
 use log::{info,warn,error,trace,debug};
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use std::io::prelude::*;
-use std::collections::HashMap;
-use linkcheck::validation::{resolve_link,Options};
+use tokio::io::{AsyncRead,AsyncReadExt,AsyncWrite,AsyncWriteExt};
+use std::time::Duration;
+use tokio::time::timeout;
+use crate::{DomainMap,Website};
+use crate::dispatch;
+use crate::error_pages;
+use crate::locations;
+use crate::http3;
+use crate::body::Body;
+use crate::h2c;
+use crate::http2;
 
 
 #[tokio::main]
-pub async fn handle_http1_1(access: &str,port_no : u16, resource_path : &'static str, is_virtually_shared : bool, domain_map : Option<HashMap<&'static str,[&'static str;3]>>) -> io::Result<()> {
+pub async fn handle_http1_1(website: Website, domain_map : Option<DomainMap>) -> io::Result<()> {
+    let website: &'static Website = Box::leak(Box::new(website));
+    let port_no = website.port_no;
     info!("Thread created for HTTP port no : {}",port_no);
-    let addr: std::net::SocketAddr = if access == "Local" {
+    let addr: std::net::SocketAddr = if website.access == "Local" {
         std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),port_no)
     }
     else {
         std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),port_no)
     };
+    let domain_map: Option<&'static DomainMap> = domain_map.map(|domain_map| &*Box::leak(Box::new(domain_map)));
     let listener = TcpListener::bind(addr).await.unwrap();
     loop {
         let (stream, peer_addr) = listener.accept().await.unwrap();
         info!("{} HTTP/1.1 Hello : {}",port_no,peer_addr);
-        let temp = domain_map.clone();
         let fut = async move {
-            handle_connection(stream, resource_path, is_virtually_shared,temp,port_no).await
+            handle_connection(stream, website, domain_map, peer_addr, "http").await
         };
         tokio::spawn(async move {
             if let Err(err) = fut.await {
@@ -36,92 +45,481 @@ pub async fn handle_http1_1(access: &str,port_no : u16, resource_path : &'static
     }
 }
 
-async fn handle_connection(mut stream: TcpStream,resource_path: &str,is_virtually_shared : bool, domain_map : Option<HashMap<&'static str,[&'static str;3]>>,port_no : u16) -> io::Result<()> {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
-    trace!("{} REQUEST: {}", port_no ,String::from_utf8_lossy(&buffer));
-    let mut headers = [httparse::EMPTY_HEADER; 16];
-    let mut req = httparse::Request::new(&mut headers);
-    req.parse(&buffer).unwrap().unwrap();
-    let mut path = req.path.unwrap().to_string();
-    if path == "/" {
-        path = path + "index.html";
-    }
-    debug!("{} {}",port_no,path);
-    let content_type = mime_guess::from_path(&path);
-    let (status,contents) = if is_virtually_shared {
-        let mut hostname : &str = "";
-        for header in req.headers {
-            if header.name == "Host" {
-                hostname = std::str::from_utf8(header.value).unwrap();
+/// Serves the HTTP/1.1 requests of a connection, over TCP or TLS. `scheme`
+/// is `http` or `https` accordingly.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, website: &'static Website, domain_map : Option<&'static DomainMap>, peer_addr: std::net::SocketAddr, scheme: &str) -> io::Result<()> {
+    let port_no = website.port_no;
+    let keep_alive_timeout = Duration::from_secs(website.keep_alive_timeout);
+    let mut buffer = BytesMut::with_capacity(1024);
+    // h2c is per port, on when any of its websites has it.
+    let h2c = scheme == "http" && (website.h2c || domain_map.iter().flat_map(|domain_map| domain_map.values()).any(|site| site.h2c));
+    if h2c && h2c::has_preface(&mut stream, &mut buffer, keep_alive_timeout).await? {
+        info!("{} h2c Hello : {}",port_no,peer_addr);
+        return http2::serve_connection(h2c::prior_knowledge(stream, buffer), website, domain_map, peer_addr).await;
+    }
+    // Requests are answered one after another, so pipelined requests already
+    // sitting in the buffer get their responses in the order they were sent.
+    while let Some(request) = read_request(&mut stream, &mut buffer, website).await? {
+        let request = match request {
+            Ok(request) => request,
+            Err(status) => {
+                warn!("{} {} Rejected request",port_no,status.as_str());
+                let (response,contents) = error_pages::response(website, status).await;
+                write_response(&mut stream, response.header("Connection", "close"), contents).await?;
+                break;
+            }
+        };
+        trace!("{} REQUEST: {:?}", port_no ,request);
+        if h2c {
+            if let Some(upgrade) = h2c::upgrade_of(&request) {
+                info!("{} h2c Upgrade : {}",port_no,peer_addr);
+                let stream = h2c::upgrade(stream, buffer, upgrade, keep_alive_timeout).await?;
+                return http2::serve_connection(stream, website, domain_map, peer_addr).await;
             }
         }
-        read_web_docs(
-            validate_path(domain_map.unwrap()[hostname][0],&path), 
-            content_type.first_or(mime_guess::mime::TEXT_HTML),
-            port_no).await
-    } 
-    else {
-        read_web_docs(
-        validate_path(resource_path,&path),
-        content_type.first_or(mime_guess::mime::TEXT_HTML),
-        port_no).await
-    };
-    let response = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nServer: Lightron/0.1.0\r\n\r\n", status.as_str(), status.canonical_reason().unwrap(),content_type.first_or(mime_guess::mime::TEXT_HTML)).into_bytes();
-    stream.write_all(&response).await.unwrap();
-    stream.write_all(&contents).await.unwrap();
-    stream.flush().await.unwrap();
+        let mut keep_alive = is_keep_alive(&request);
+        // Whether the body was read for the upstream, and read whole.
+        let mut body_read = None;
+        let (site, mut response, mut contents) = {
+            let (stream, buffer, body_read, request) = (&mut stream, &mut buffer, &mut body_read, &request);
+            dispatch::dispatch(website, domain_map, request, peer_addr, scheme, move |limit| async move {
+                let body = read_body(stream, buffer, request, limit, keep_alive_timeout).await;
+                *body_read = Some(matches!(body, Ok(Ok(_))));
+                body
+            }).await?
+        };
+        match body_read {
+            Some(true) => {},
+            // What is left of the body can't be told from the next request.
+            Some(false) => keep_alive = false,
+            // Chunked request bodies are not read, so the connection can't be reused.
+            None if request.headers().contains_key(TRANSFER_ENCODING) => keep_alive = false,
+            None if content_length(&request) as u64 > website.max_body_size => keep_alive = false,
+            None => discard_body(&mut stream, &mut buffer, content_length(&request), keep_alive_timeout).await?,
+        }
+        // Compressed bodies are only sized once they are sent, HTTP/1.0
+        // clients learn where such a body ends from the connection closing.
+        let chunked = contents.len().is_none() && request.version() != Version::HTTP_10;
+        if contents.len().is_none() {
+            if chunked {
+                response = response.header("Transfer-Encoding", "chunked");
+            }
+            else {
+                keep_alive = false;
+            }
+        }
+        if !keep_alive {
+            response = response.header("Connection", "close");
+        }
+        else if request.version() == Version::HTTP_10 {
+            response = response.header("Connection", "keep-alive").header("Keep-Alive", format!("timeout={}", keep_alive_timeout.as_secs()));
+        }
+        let mut response = response.header("Server", "Lightron/0.1.0").body(()).unwrap();
+        locations::finish(&mut response, site);
+        if scheme == "https" {
+            http3::advertise(&mut response, site);
+        }
+        write_head(&mut stream, &response).await?;
+        if request.method() != Method::HEAD {
+            while let Some(chunk) = contents.next_chunk().await? {
+                if chunked {
+                    stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await?;
+                    stream.write_all(&chunk).await?;
+                    stream.write_all(b"\r\n").await?;
+                }
+                else {
+                    stream.write_all(&chunk).await?;
+                }
+            }
+            if chunked {
+                stream.write_all(b"0\r\n\r\n").await?;
+            }
+        }
+        stream.flush().await?;
+        if !keep_alive {
+            break;
+        }
+    }
     Ok(()) as io::Result<()>
 }
 
-fn validate_path(parent_path : &str, file_path : &str) -> std::path::PathBuf {
-    let (modified_parent_path,modified_file_path) = if cfg!(target_os = "windows") {
-        (parent_path.replace("/", "\\"),file_path.replace("/", "\\"))
+/// Reads the next request head from the connection, keeping any bytes of
+/// pipelined requests that follow it in `buffer`. Returns `None` once the peer
+/// closes the connection or stays idle for longer than the keep-alive timeout,
+/// and `Some(Err(status))` when the head is malformed or exceeds the header
+/// limits of the website.
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, buffer: &mut BytesMut, website: &Website) -> io::Result<Option<Result<Request<()>,StatusCode>>> {
+    let mut header_count = website.max_header_count.min(16);
+    loop {
+        if !buffer.is_empty() {
+            let mut headers = vec![httparse::EMPTY_HEADER; header_count];
+            let mut req = httparse::Request::new(&mut headers);
+            match req.parse(buffer) {
+                Ok(httparse::Status::Complete(head_len)) => {
+                    if head_len > website.max_header_size {
+                        return Ok(Some(Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)));
+                    }
+                    let mut builder = Request::builder()
+                        .method(req.method.unwrap())
+                        .uri(req.path.unwrap())
+                        .version(if req.version == Some(0) { Version::HTTP_10 } else { Version::HTTP_11 });
+                    for header in req.headers.iter() {
+                        builder = builder.header(header.name, header.value);
+                    }
+                    let request = match builder.body(()) {
+                        Ok(request) => request,
+                        Err(_) => return Ok(Some(Err(StatusCode::BAD_REQUEST))),
+                    };
+                    // Where the body ends has to be certain, or its bytes
+                    // would be read as the next request.
+                    if declared_length(&request).is_none() {
+                        return Ok(Some(Err(StatusCode::BAD_REQUEST)));
+                    }
+                    buffer.advance(head_len);
+                    return Ok(Some(Ok(request)));
+                },
+                Ok(httparse::Status::Partial) => {
+                    if buffer.len() > website.max_header_size {
+                        return Ok(Some(Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)));
+                    }
+                },
+                Err(httparse::Error::TooManyHeaders) => {
+                    if header_count >= website.max_header_count {
+                        return Ok(Some(Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)));
+                    }
+                    header_count = (header_count * 2).min(website.max_header_count);
+                    continue;
+                },
+                Err(_) => return Ok(Some(Err(StatusCode::BAD_REQUEST))),
+            }
+        }
+        match timeout(Duration::from_secs(website.keep_alive_timeout), stream.read_buf(buffer)).await {
+            Ok(Ok(0)) => return Ok(None),
+            Ok(Ok(_)) => {},
+            Ok(Err(err)) => return Err(err),
+            Err(_) => {
+                debug!("{} Closing idle connection",website.port_no);
+                return Ok(None);
+            }
+        }
     }
-    else {
-        (parent_path.to_string(),file_path.to_string())
-    };
-    let linkcheck_options = Options::new().with_root_directory(modified_parent_path.clone()).unwrap().set_links_may_traverse_the_root_directory(false);
-    resolve_link(std::path::Path::new(&modified_parent_path),std::path::Path::new(&modified_file_path),&linkcheck_options).unwrap_or(
-        if cfg!(target_os = "windows") {
-            std::path::PathBuf::from("C:\\Program Files\\Common Files\\Lightron\\403.html")
+}
+
+/// Drops `len` bytes of request body so the next pipelined request starts at
+/// the front of `buffer`.
+async fn discard_body<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, buffer: &mut BytesMut, mut len: usize, keep_alive_timeout: Duration) -> io::Result<()> {
+    loop {
+        let buffered = len.min(buffer.len());
+        buffer.advance(buffered);
+        len -= buffered;
+        if len == 0 {
+            return Ok(());
         }
-        else {
-            std::path::PathBuf::from("/var/www/403.html")
+        fill(stream, buffer, keep_alive_timeout).await?;
+    }
+}
+
+/// Reads the body of a request, sized by `Content-Length` or chunked. The
+/// inner error is the status to answer with when the body is larger than
+/// `limit` or its chunks are malformed.
+async fn read_body<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, buffer: &mut BytesMut, request: &Request<()>, limit: u64, keep_alive_timeout: Duration) -> io::Result<Result<Bytes,StatusCode>> {
+    let chunked = request.headers().get(TRANSFER_ENCODING).map(|coding| coding.to_str().map(|coding| coding.to_ascii_lowercase().contains("chunked")).unwrap_or(false));
+    let len = content_length(request);
+    if chunked == Some(false) || (chunked.is_none() && len as u64 > limit) {
+        return Ok(Err(if chunked.is_some() { StatusCode::NOT_IMPLEMENTED } else { StatusCode::PAYLOAD_TOO_LARGE }));
+    }
+    let expects_continue = request.headers().get(EXPECT).map(|expect| expect.as_bytes().eq_ignore_ascii_case(b"100-continue")).unwrap_or(false);
+    if expects_continue && request.version() == Version::HTTP_11 {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+    }
+    if chunked.is_none() {
+        while buffer.len() < len {
+            fill(stream, buffer, keep_alive_timeout).await?;
         }
-    )
+        return Ok(Ok(buffer.split_to(len).freeze()));
+    }
+    let mut body = BytesMut::new();
+    loop {
+        let line_end = loop {
+            if let Some(line_end) = buffer.windows(2).position(|window| window == b"\r\n") {
+                break line_end;
+            }
+            if buffer.len() > 4096 {
+                return Ok(Err(StatusCode::BAD_REQUEST));
+            }
+            fill(stream, buffer, keep_alive_timeout).await?;
+        };
+        let size = std::str::from_utf8(&buffer[..line_end]).ok()
+            .map(|line| line.split(';').next().unwrap_or("").trim())
+            // Sizes that fit in 64 bits, in hex digits only.
+            .filter(|digits| !digits.is_empty() && digits.len() <= 16 && digits.bytes().all(|digit| digit.is_ascii_hexdigit()))
+            .and_then(|digits| u64::from_str_radix(digits, 16).ok());
+        buffer.advance(line_end + 2);
+        let size = match size {
+            Some(0) => break,
+            // Compared with what is left, a size near the 64 bit limit
+            // would overflow the total.
+            Some(size) if size <= limit.saturating_sub(body.len() as u64) => size as usize,
+            Some(_) => return Ok(Err(StatusCode::PAYLOAD_TOO_LARGE)),
+            None => return Ok(Err(StatusCode::BAD_REQUEST)),
+        };
+        while buffer.len() < size + 2 {
+            fill(stream, buffer, keep_alive_timeout).await?;
+        }
+        body.extend_from_slice(&buffer[..size]);
+        buffer.advance(size + 2);
+    }
+    // Trailers end with an empty line, they are not passed on.
+    loop {
+        match buffer.windows(2).position(|window| window == b"\r\n") {
+            Some(0) => {
+                buffer.advance(2);
+                return Ok(Ok(body.freeze()));
+            },
+            Some(line_end) => buffer.advance(line_end + 2),
+            None if buffer.len() > 4096 => return Ok(Err(StatusCode::BAD_REQUEST)),
+            None => fill(stream, buffer, keep_alive_timeout).await?,
+        }
+    }
 }
 
+/// Reads more of the connection into `buffer`.
+async fn fill<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, buffer: &mut BytesMut, keep_alive_timeout: Duration) -> io::Result<()> {
+    match timeout(keep_alive_timeout, stream.read_buf(buffer)).await {
+        Ok(Ok(0)) => Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(err)) => Err(err),
+        Err(_) => Err(io::ErrorKind::TimedOut.into()),
+    }
+}
 
-async fn read_web_docs(file_name : std::path::PathBuf, content_type : mime_guess::Mime, port_no : u16) -> (StatusCode,Vec<u8>) {
-    let src_file = File::open(file_name);
-    let mut file_contents = Vec::new();
-    let status:StatusCode;
-    file_contents = match src_file {
-        Ok(mut file) => {
-            file.read_to_end(&mut file_contents).unwrap();
-            status = StatusCode::OK;
-            file_contents
-        },
-        Err(_) => { 
-            warn!("{} 404 Triggered",port_no);
-            status = StatusCode::NOT_FOUND;
-            if content_type == mime_guess::mime::TEXT_HTML {
-                let mut file_404 = if cfg!(target_os = "windows") {
-                        File::open("C:\\Program Files\\Common Files\\Lightron\\404.html").unwrap()
-                    }
-                    else {
-                        File::open("/var/www/404.html").unwrap()
-                    };
-                let mut file_404_string = Vec::new();
-                file_404.read_to_end(&mut file_404_string).unwrap();
-                file_404_string
+/// Writes a whole response with a sized body, e.g. the error page of a
+/// request the connection is closed after.
+async fn write_response<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, response: Builder, mut contents: Body) -> io::Result<()> {
+    let response = response.header("Server", "Lightron/0.1.0").body(()).unwrap();
+    write_head(stream, &response).await?;
+    while let Some(chunk) = contents.next_chunk().await? {
+        stream.write_all(&chunk).await?;
+    }
+    stream.flush().await
+}
+
+/// Writes the status line and headers of `response` in HTTP/1.1 form.
+async fn write_head<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, response: &Response<()>) -> io::Result<()> {
+    let status = response.status();
+    let mut head = format!("HTTP/1.1 {} {}\r\n", status.as_str(), status.canonical_reason().unwrap_or("")).into_bytes();
+    for (name, value) in response.headers() {
+        head.extend_from_slice(header_case(name.as_str()).as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head).await
+}
+
+/// `http` keeps header names lowercase, HTTP/1.1 clients are used to seeing
+/// them as `Content-Type`.
+fn header_case(name: &str) -> String {
+    if name == "etag" {
+        return "ETag".to_string();
+    }
+    name.split('-').map(|word| {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+            None => String::new(),
+        }
+    }).collect::<Vec<String>>().join("-")
+}
+
+fn content_length(request: &Request<()>) -> usize {
+    declared_length(request).unwrap_or(0)
+}
+
+/// The body length `Content-Length` declares, 0 without one. `None` when a
+/// value isn't a number, or the header is repeated with different values.
+fn declared_length(request: &Request<()>) -> Option<usize> {
+    let mut declared = None;
+    for value in request.headers().get_all(CONTENT_LENGTH) {
+        for len in value.to_str().ok()?.split(',') {
+            let len = len.trim();
+            if len.is_empty() || !len.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
             }
-            else
-            {
-                vec![] 
+            let len: usize = len.parse().ok()?;
+            if declared.map(|declared| declared != len).unwrap_or(false) {
+                return None;
             }
-        },
-    };
-    (status,file_contents)
-}
\ No newline at end of file
+            declared = Some(len);
+        }
+    }
+    Some(declared.unwrap_or(0))
+}
+
+/// HTTP/1.1 connections persist unless the client asks to close them, HTTP/1.0
+/// connections only persist when the client asks for `keep-alive`.
+fn is_keep_alive(request: &Request<()>) -> bool {
+    let connection = request.headers().get(CONNECTION).and_then(|value| value.to_str().ok()).unwrap_or("").to_ascii_lowercase();
+    let mut options = connection.split(',').map(|option| option.trim());
+    if request.version() == Version::HTTP_10 {
+        options.any(|option| option == "keep-alive")
+    }
+    else {
+        !options.any(|option| option == "close")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Sends `requests` on a connection to `website` and reads what comes
+    /// back until the server closes it.
+    async fn exchange(website: &'static Website, requests: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(1 << 20);
+        let peer_addr = "127.0.0.1:40000".parse().unwrap();
+        let server = tokio::spawn(handle_connection(server, website, None, peer_addr, "http"));
+        client.write_all(requests).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut responses = Vec::new();
+        client.read_to_end(&mut responses).await.unwrap();
+        server.await.unwrap().unwrap();
+        String::from_utf8_lossy(&responses).into_owned()
+    }
+
+    #[tokio::test]
+    async fn invalid_content_length_is_rejected() {
+        let website = testing::leak(testing::website(""));
+        for length in ["abc", "-1", "+5", "5 5", "", "5, 6"] {
+            let request = format!("POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n", length);
+            let response = exchange(website, request.as_bytes()).await;
+            assert!(response.starts_with("HTTP/1.1 400 "), "{:?} : {}", length, response);
+            assert!(response.contains("Connection: close\r\n"), "{:?} : {}", length, response);
+        }
+    }
+
+    #[tokio::test]
+    async fn conflicting_content_lengths_are_rejected() {
+        let website = testing::leak(testing::website(""));
+        let response = exchange(website, b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nGET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", response);
+    }
+
+    #[test]
+    fn repeated_equal_content_lengths_are_one() {
+        let request = Request::builder().header(CONTENT_LENGTH, "5").header(CONTENT_LENGTH, "5, 5").body(()).unwrap();
+        assert_eq!(declared_length(&request), Some(5));
+        assert_eq!(declared_length(&Request::builder().body(()).unwrap()), Some(0));
+    }
+
+    /// Reads `body` as the chunked body of a request, up to `limit` bytes.
+    async fn read_chunked(body: &[u8], limit: u64) -> Result<Bytes,StatusCode> {
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let mut buffer = BytesMut::from(body);
+        let request = Request::post("/").header(TRANSFER_ENCODING, "chunked").body(()).unwrap();
+        read_body(&mut server, &mut buffer, &request, limit, Duration::from_secs(1)).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn chunked_body_is_read() {
+        assert_eq!(read_chunked(b"1\r\na\r\n3;ext=1\r\nbcd\r\n0\r\n\r\n", 16).await, Ok(Bytes::from("abcd")));
+    }
+
+    #[tokio::test]
+    async fn huge_chunk_size_is_too_large() {
+        assert_eq!(read_chunked(b"1\r\na\r\nffffffffffffffff\r\n", 1 << 20).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(read_chunked(b"5\r\nabcde\r\n5\r\n", 8).await, Err(StatusCode::PAYLOAD_TOO_LARGE));
+    }
+
+    #[tokio::test]
+    async fn malformed_chunk_size_is_rejected() {
+        for size in ["10000000000000000", "0000000000000000001", "+5", "-1", "", "g"] {
+            let body = format!("{}\r\n", size);
+            assert_eq!(read_chunked(body.as_bytes(), 1 << 20).await, Err(StatusCode::BAD_REQUEST), "{:?}", size);
+        }
+    }
+
+    #[tokio::test]
+    async fn body_is_not_read_as_the_next_request() {
+        let website = testing::leak(testing::website(""));
+        // The body looks like a request of its own, it's skipped as a body.
+        let body = "GET /secret HTTP/1.1\r\n\r\n";
+        let requests = format!("POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let response = exchange(website, requests.as_bytes()).await;
+        assert_eq!(response.matches("HTTP/1.1 ").count(), 1, "{}", response);
+        assert!(response.starts_with("HTTP/1.1 405 "), "{}", response);
+    }
+
+    /// The value of `name` in the first response head of `response`.
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        let head = &response[..response.find("\r\n\r\n").unwrap()];
+        head.lines().skip(1).find_map(|line| line.split_once(": ").filter(|(line_name, _)| line_name.eq_ignore_ascii_case(name)).map(|(_, value)| value))
+    }
+
+    #[tokio::test]
+    async fn file_response_is_dated_sized_and_validated() {
+        let resource = testing::resource(&[("a.txt", b"hello")]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\n", resource)));
+        let response = exchange(website, b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(httpdate::parse_http_date(header(&response, "Date").unwrap()).is_ok(), "{}", response);
+        assert_eq!(header(&response, "Content-Length"), Some("5"));
+        assert!(httpdate::parse_http_date(header(&response, "Last-Modified").unwrap()).is_ok(), "{}", response);
+        assert!(header(&response, "Transfer-Encoding").is_none());
+        assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn error_response_is_dated_and_sized() {
+        let resource = testing::resource(&[]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\n", resource)));
+        let response = exchange(website, b"GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+        assert!(header(&response, "Date").is_some());
+        let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+        assert_eq!(header(&response, "Content-Length"), Some(body.len().to_string().as_str()));
+    }
+
+    #[tokio::test]
+    async fn compressed_response_is_chunked() {
+        let text = "lightron ".repeat(1000);
+        let resource = testing::resource(&[("a.txt", text.as_bytes())]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\ncompression = true\n", resource)));
+        let response = exchange(website, b"GET /a.txt HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(header(&response, "Date").is_some());
+        assert!(header(&response, "Last-Modified").is_some());
+        assert_eq!(header(&response, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(&response, "Transfer-Encoding"), Some("chunked"));
+        assert!(header(&response, "Content-Length").is_none());
+        assert!(response.ends_with("\r\n0\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_response_has_the_headers_of_get() {
+        let resource = testing::resource(&[("a.txt", b"hello")]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\n", resource)));
+        let response = exchange(website, b"HEAD /a.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(header(&response, "Content-Length"), Some("5"));
+        assert!(header(&response, "Last-Modified").is_some());
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn h2c_is_on_when_any_site_of_the_port_has_it() {
+        let resource = testing::resource(&[("a.txt", b"hello")]);
+        let first = testing::leak(testing::website(&format!("name = \"a.example\"\nresource = {:?}\n", resource)));
+        let second = testing::leak(testing::website(&format!("name = \"b.example\"\nresource = {:?}\nh2c = true\n", resource)));
+        let domain_map: &'static DomainMap = Box::leak(Box::new(DomainMap::from([("a.example", first), ("b.example", second)])));
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let peer_addr = "127.0.0.1:40000".parse().unwrap();
+        tokio::spawn(handle_connection(server, first, Some(domain_map), peer_addr, "http"));
+        let (mut send_request, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let (response, _) = send_request.send_request(Request::get("http://b.example/a.txt").body(()).unwrap(), true).unwrap();
+        assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+    }
+}
@@ -1,103 +1,36 @@
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::{self, BufReader};
+use std::io;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig, ResolvesServerCertUsingSNI , sign::{CertifiedKey,RSASigningKey}};
+use tokio::io::{AsyncRead,AsyncWrite};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig, ResolvesServerCertUsingSNI, Session, sign::CertifiedKey};
 use tokio_rustls::TlsAcceptor;
-use rustls_pemfile;
-use http::{Response,StatusCode,Version,Request};
-use h2::server;
+use http::{Method,Response,StatusCode,Version,Request};
+use http::header::{HeaderValue,SERVER};
+use h2::server::{self,SendResponse};
+use h2::{RecvStream,SendStream};
+use std::future::poll_fn;
 use bytes::Bytes;
-use std::collections::HashMap;
 use simplelog::*;
-use log::{info,error,trace,debug};
-use linkcheck::validation::{resolve_link,Options};
-
-fn load_certs(filename: &str) -> Vec<Certificate> {
-    let certfile = File::open(filename).unwrap();
-    let mut reader = BufReader::new(certfile);
-    rustls_pemfile::certs(&mut reader).unwrap()
-        .iter()
-        .map(|v| Certificate(v.clone()))
-        .collect()
-}
-
-fn load_private_key(filename: &str) -> PrivateKey {
-    let keyfile = File::open(filename).unwrap();
-    let mut reader = BufReader::new(keyfile);
-
-    loop {
-        match rustls_pemfile::read_one(&mut reader).unwrap() {
-            Some(rustls_pemfile::Item::RSAKey(key)) => return PrivateKey(key),
-            Some(rustls_pemfile::Item::PKCS8Key(key)) => return PrivateKey(key),
-            None => break,
-            _ => {}
-        }
-    }
-
-    panic!("no keys found in {:?} (encrypted keys not supported)", filename);
-}
-
-async fn read_web_docs(file_name : std::path::PathBuf, content_type : mime_guess::Mime, port_no : u16) -> (StatusCode,Vec<u8>) {
-    let src_file = File::open(file_name);
-    let mut file_contents = Vec::new();
-    let status:StatusCode;
-    file_contents = match src_file {
-        Ok(mut file) => {
-            file.read_to_end(&mut file_contents).unwrap();
-            status = StatusCode::OK;
-            file_contents
-        },
-        Err(_) => { 
-            log::warn!("{} 404 Triggered",port_no);
-            status = StatusCode::NOT_FOUND;
-            if content_type == mime_guess::mime::TEXT_HTML {
-                let mut file_404 = if cfg!(target_os = "windows") {
-                        File::open("assets\\404.html").unwrap()
-                    }
-                    else {
-                        File::open("assets/404.html").unwrap()
-                    };
-                let mut file_404_string = Vec::new();
-                file_404.read_to_end(&mut file_404_string).unwrap();
-                file_404_string
-            }
-            else
-            {
-                vec![] 
-            }
-        },
-    };
-    (status,file_contents)
-}
-
-fn validate_path(parent_path : &str, file_path : &str) -> std::path::PathBuf {
-    let (modified_parent_path,modified_file_path) = if cfg!(target_os = "windows") {
-        (parent_path.replace("/", "\\"),file_path.replace("/", "\\"))
-    }
-    else {
-        (parent_path.to_string(),file_path.to_string())
-    };
-    let linkcheck_options = Options::new().with_root_directory(modified_parent_path.clone()).unwrap().set_links_may_traverse_the_root_directory(false);
-    resolve_link(std::path::Path::new(&modified_parent_path),std::path::Path::new(&modified_file_path),&linkcheck_options).unwrap_or(
-        if cfg!(target_os = "windows") {
-            std::path::PathBuf::from("assets\\403.html")
-        }
-        else {
-            std::path::PathBuf::from("assets/403.html")
-        }
-    )
-}
-
-
+use log::{info,warn,error,trace,debug};
+use crate::{DomainMap,Website};
+use crate::dispatch;
+use crate::proxy;
+use crate::http1_1;
+use crate::locations;
+use crate::http3;
+use crate::keys::{load_certs,load_private_key,signing_key};
+use crate::body::Body;
+use std::time::SystemTime;
+use httpdate::fmt_http_date;
 
 #[tokio::main]
-pub async fn handle_http2(access: &str,port_no:u16, cert_path:&str, priv_path:&str, resource_path:&'static str, push_files:Vec<String>, is_virtually_shared : bool, domain_map : Option<HashMap<&'static str,[&'static str;3]>>) -> io::Result<()> {
+pub async fn handle_http2(website: Website, is_virtually_shared : bool, domain_map : Option<DomainMap>) -> io::Result<()> {
+    let website: &'static Website = Box::leak(Box::new(website));
+    let port_no = website.port_no;
     let mut log_config = ConfigBuilder::new();
     log_config.set_time_to_local(true);
     info!("Thread created for HTTPS port no : {}",port_no);
-    let addr: std::net::SocketAddr = if access == "Local" {
+    let addr: std::net::SocketAddr = if website.access == "Local" {
         std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),port_no)
     }
     else {
@@ -106,87 +39,35 @@ pub async fn handle_http2(access: &str,port_no:u16, cert_path:&str, priv_path:&s
     let mut config = ServerConfig::new(NoClientAuth::new());
     if is_virtually_shared {
         let mut resolver = ResolvesServerCertUsingSNI::new();
-        for (domain_name,others) in domain_map.clone().unwrap() {
-            let cred : CertifiedKey = CertifiedKey::new(load_certs(others[1]),Arc::new(Box::new(RSASigningKey::new(&load_private_key(others[2])).unwrap())));
+        for (domain_name,site) in domain_map.clone().unwrap() {
+            let cred : CertifiedKey = CertifiedKey::new(load_certs(&site.certificate),signing_key(&load_private_key(site),&site.private_key));
             resolver.add(domain_name, cred).unwrap();
         } 
         config.cert_resolver = Arc::new(resolver);
     }
     else {
-        let certs = load_certs(cert_path);
-        let keys = load_private_key(&priv_path);
+        let certs = load_certs(&website.certificate);
+        let keys = load_private_key(website);
         config.set_single_cert(certs, keys).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)).unwrap();
     }
     config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let domain_map: Option<&'static DomainMap> = domain_map.map(|domain_map| &*Box::leak(Box::new(domain_map)));
     
     let acceptor = TlsAcceptor::from(Arc::new(config));
     let listener = TcpListener::bind(&addr).await.unwrap();   
     loop {
         let (stream, peer_addr) = listener.accept().await.unwrap();
         let acceptor = acceptor.clone();
-        let push_files = push_files.clone();
-        let domain_map_clone = domain_map.clone();
         let fut = async move {
-            let tls_stream = acceptor.accept(stream).await.unwrap();
-            let mut connection = server::handshake(tls_stream).await.unwrap();
-            info!("{} HTTP/2 Hello: {}", port_no ,peer_addr);
-            while let Some(result) = connection.accept().await {
-                let (request, mut respond) = result.unwrap();
-                trace!("{} REQUEST : {:?}", port_no ,request);
-                let mut path = request.uri().path().to_string();
-                if request.uri().path() == "/" {
-                    path = path + "index.html";
-                    let pushed_uri_auth: &str = &(request.uri().scheme_str().unwrap().to_string() + "://" + &request.uri().authority().unwrap().to_string());
-                    debug!("{} pushed_path : {}",port_no,pushed_uri_auth);
-                    for file in &push_files {
-                        let pushed_req = Request::builder()
-                            .uri(pushed_uri_auth.to_string() + "/" + file)
-                            .body(())
-                            .unwrap();
-                        let content_type = mime_guess::from_path(&file);
-                        let pushed_rsp = http::Response::builder().status(200).header("Content-Type", format!("{}",content_type.first_or(mime_guess::mime::TEXT_HTML))).body(()).unwrap();
-                        let mut send_pushed = respond
-                            .push_request(pushed_req)
-                            .unwrap()
-                            .send_response(pushed_rsp, false)
-                            .unwrap();
-                        if is_virtually_shared {
-                            let mut push_file = if cfg!(target_os = "windows") {
-                                File::open(domain_map_clone.clone().unwrap()[request.uri().authority().unwrap().as_str()][0].to_string() + "\\" + &file.replace('/',"\\")).unwrap()
-                            }
-                            else {
-                                File::open(domain_map_clone.clone().unwrap()[request.uri().authority().unwrap().as_str()][0].to_string() + "/" + file).unwrap()
-                            };
-                            let mut push_contents = Vec::new();
-                            push_file.read_to_end(&mut push_contents).unwrap();
-                            send_pushed.send_data(Bytes::from(push_contents), true).unwrap();
-                        }
-                        else {
-                            let mut push_file = if cfg!(target_os = "windows") {
-                                File::open(resource_path.to_string() + "\\" + &file.replace('/',"\\")).unwrap()
-                            }
-                            else {
-                                File::open(resource_path.to_string() + "/" + file).unwrap()
-                            };
-                            let mut push_contents = Vec::new();
-                            push_file.read_to_end(&mut push_contents).unwrap();
-                            send_pushed.send_data(Bytes::from(push_contents), true).unwrap();
-                        }
-                    }
-                }
-                let content_type = mime_guess::from_path(&path);
-                debug!("{} path : {}",port_no,path);
-                let (status,contents) = if is_virtually_shared {
-                    read_web_docs(validate_path(domain_map_clone.clone().unwrap()[request.uri().authority().unwrap().as_str()][0],&path),content_type.first_or(mime_guess::mime::TEXT_HTML),port_no).await
-                }
-                else {
-                    read_web_docs(validate_path(resource_path,&path),content_type.first_or(mime_guess::mime::TEXT_HTML),port_no).await
-                };
-                let response = Response::builder().version(Version::HTTP_2).status(status).header("Content-Type", format!("{}",content_type.first_or(mime_guess::mime::TEXT_HTML))).header("Server", "Lightron/0.1.0").body(()).unwrap();                
-                let mut send = respond.send_response(response, false).unwrap();
-                send.send_data(Bytes::from(contents),true).unwrap();
+            let tls_stream = acceptor.accept(stream).await?;
+            // Clients that don't speak h2 get HTTP/1.1 over the same TLS
+            // connection.
+            if tls_stream.get_ref().1.get_alpn_protocol() != Some(b"h2") {
+                info!("{} HTTP/1.1 over TLS Hello: {}", port_no ,peer_addr);
+                return http1_1::handle_connection(tls_stream, website, domain_map, peer_addr, "https").await;
             }
-            Ok(()) as io::Result<()>
+            info!("{} HTTP/2 Hello: {}", port_no ,peer_addr);
+            serve_connection(tls_stream, website, domain_map, peer_addr).await
         };
 
         tokio::spawn(async move {
@@ -195,4 +76,224 @@ pub async fn handle_http2(access: &str,port_no:u16, cert_path:&str, priv_path:&s
             }
         });
     }
-}
\ No newline at end of file
+}
+
+/// Serves the HTTP/2 streams of a connection, over TLS or, for h2c, TCP.
+pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, website: &'static Website, domain_map: Option<&'static DomainMap>, peer_addr: std::net::SocketAddr) -> io::Result<()> {
+    let port_no = website.port_no;
+    let mut connection = server::handshake(stream).await.map_err(h2_error)?;
+    while let Some(result) = connection.accept().await {
+        let (request, respond) = result.map_err(h2_error)?;
+        // Each stream gets its own task, the connection has to keep
+        // being polled for window updates while a body is sent.
+        tokio::spawn(async move {
+            if let Err(err) = handle_request(request, respond, website, domain_map, peer_addr).await {
+                error!("{} {:?}", port_no,err);
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn handle_request(request: Request<RecvStream>, mut respond: SendResponse<Bytes>, website: &Website, domain_map : Option<&DomainMap>, peer_addr: std::net::SocketAddr) -> io::Result<()> {
+    let port_no = website.port_no;
+    trace!("{} REQUEST : {:?}", port_no ,request);
+    // h2c requests come in as `http`.
+    let scheme = if request.uri().scheme_str() == Some("http") { "http" } else { "https" };
+    let (parts, recv) = request.into_parts();
+    let request = Request::from_parts(parts, ());
+    let (site,response,contents) = dispatch::dispatch(website, domain_map, &request, peer_addr, scheme, |limit| dispatch::read_body(recv, limit)).await?;
+    let response = response.body(()).unwrap();
+    // Files are only pushed alongside the page they go with, never with an
+    // error, a redirect or an upstream's answer.
+    if request.uri().path() == "/" && request.method() == Method::GET && response.status() == StatusCode::OK && !proxy::is_proxied(site) {
+        push(&mut respond, &request, site).await;
+    }
+    send_response(respond, response, contents, site, request.method() == Method::HEAD).await
+}
+
+/// Pushes the website's `push_protocol_files` for the request of `/`.
+async fn push(respond: &mut SendResponse<Bytes>, request: &Request<()>, site: &Website) {
+    let port_no = site.port_no;
+    let pushed_uri_auth: &str = &(request.uri().scheme_str().unwrap().to_string() + "://" + request.uri().authority().unwrap().as_ref());
+    debug!("{} pushed_path : {}",port_no,pushed_uri_auth);
+    for file in &site.push_protocol_files {
+        // A file that can't be pushed is left for the client to ask for,
+        // the response it was meant to go with is still sent.
+        let path = if cfg!(target_os = "windows") {
+            site.resource.clone() + "\\" + &file.replace('/',"\\")
+        }
+        else {
+            site.resource.clone() + "/" + file
+        };
+        let (push_file, metadata) = match open_push_file(&path).await {
+            Ok(opened) => opened,
+            Err(err) => {
+                warn!("{} push of {} skipped : {}",port_no,path,err);
+                continue;
+            }
+        };
+        let pushed_req = Request::builder()
+            .uri(pushed_uri_auth.to_string() + "/" + file)
+            .body(())
+            .unwrap();
+        let mut pushed_respond = match respond.push_request(pushed_req) {
+            Ok(pushed_respond) => pushed_respond,
+            Err(err) => {
+                debug!("{} push skipped : {}",port_no,err);
+                break;
+            }
+        };
+        let content_type = mime_guess::from_path(file);
+        let mut pushed_rsp = http::Response::builder().status(200).header("Date", fmt_http_date(SystemTime::now())).header("Content-Type", format!("{}",content_type.first_or(mime_guess::mime::TEXT_HTML))).header("Content-Length", metadata.len());
+        if let Ok(last_modified) = metadata.modified() {
+            pushed_rsp = pushed_rsp.header("Last-Modified", fmt_http_date(last_modified));
+        }
+        let mut send_pushed = match pushed_respond.send_response(pushed_rsp.body(()).unwrap(), false) {
+            Ok(send_pushed) => send_pushed,
+            Err(err) => {
+                debug!("{} push skipped : {}",port_no,err);
+                continue;
+            }
+        };
+        let push_contents = Body::from_file(push_file, metadata.len());
+        tokio::spawn(async move {
+            if let Err(err) = send_body(&mut send_pushed, push_contents).await {
+                error!("{} {:?}", port_no,err);
+            }
+        });
+    }
+}
+
+/// Opens a file to push, with its metadata.
+async fn open_push_file(path: &str) -> io::Result<(tokio::fs::File, std::fs::Metadata)> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a file"));
+    }
+    Ok((file, metadata))
+}
+
+impl dispatch::RequestBody for RecvStream {
+    async fn next_data(&mut self) -> io::Result<Option<Bytes>> {
+        let data = match self.data().await {
+            Some(data) => data.map_err(h2_error)?,
+            None => return Ok(None),
+        };
+        let _ = self.flow_control().release_capacity(data.len());
+        Ok(Some(data))
+    }
+}
+
+/// Sends the response head and, unless it's for a HEAD request, its body.
+async fn send_response(mut respond: SendResponse<Bytes>, mut response: Response<()>, contents: Body, site: &Website, head_only: bool) -> io::Result<()> {
+    *response.version_mut() = Version::HTTP_2;
+    response.headers_mut().insert(SERVER, HeaderValue::from_static("Lightron/0.1.0"));
+    locations::finish(&mut response, site);
+    http3::advertise(&mut response, site);
+    if head_only || contents.is_empty() {
+        respond.send_response(response, true).map_err(h2_error)?;
+    }
+    else {
+        let mut send = respond.send_response(response, false).map_err(h2_error)?;
+        send_body(&mut send, contents).await?;
+    }
+    Ok(())
+}
+
+/// Sends `body` on `send` chunk by chunk, never queueing more data than the
+/// peer's flow-control window has room for.
+async fn send_body(send: &mut SendStream<Bytes>, mut body: Body) -> io::Result<()> {
+    while let Some(mut chunk) = body.next_chunk().await? {
+        while !chunk.is_empty() {
+            send.reserve_capacity(chunk.len());
+            let capacity = match poll_fn(|cx| send.poll_capacity(cx)).await {
+                Some(capacity) => capacity.map_err(h2_error)?,
+                None => return Err(io::ErrorKind::BrokenPipe.into()),
+            };
+            send.send_data(chunk.split_to(capacity.min(chunk.len())), false).map_err(h2_error)?;
+        }
+    }
+    send.send_data(Bytes::new(), true).map_err(h2_error)
+}
+
+fn h2_error(err: h2::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use bytes::BytesMut;
+    use http::HeaderMap;
+
+    /// Sends a GET for `uri` with `headers` on an HTTP/2 connection to
+    /// `website`, answering the status, headers and body.
+    async fn get(website: &'static Website, uri: &str, headers: &[(&str, &str)]) -> (StatusCode, HeaderMap, Bytes) {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        let peer_addr = "127.0.0.1:40000".parse().unwrap();
+        tokio::spawn(serve_connection(server, website, None, peer_addr));
+        let (mut send_request, connection) = h2::client::handshake(client).await.unwrap();
+        tokio::spawn(connection);
+        let mut request = Request::builder().uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let (response, _) = send_request.send_request(request.body(()).unwrap(), true).unwrap();
+        let (parts, mut body) = response.await.unwrap().into_parts();
+        let mut contents = BytesMut::new();
+        while let Some(data) = body.data().await {
+            let data = data.unwrap();
+            let _ = body.flow_control().release_capacity(data.len());
+            contents.extend_from_slice(&data);
+        }
+        (parts.status, parts.headers, contents.freeze())
+    }
+
+    #[tokio::test]
+    async fn file_response_is_dated_sized_and_validated() {
+        let resource = testing::resource(&[("a.txt", b"hello")]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\n", resource)));
+        let (status, headers, body) = get(website, "http://localhost/a.txt", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(httpdate::parse_http_date(headers["date"].to_str().unwrap()).is_ok());
+        assert_eq!(headers["content-length"], "5");
+        assert!(httpdate::parse_http_date(headers["last-modified"].to_str().unwrap()).is_ok());
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn error_response_is_dated_and_sized() {
+        let resource = testing::resource(&[]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\n", resource)));
+        let (status, headers, body) = get(website, "http://localhost/missing", &[]).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(headers.contains_key("date"));
+        assert_eq!(headers["content-length"], body.len().to_string().as_str());
+    }
+
+    #[tokio::test]
+    async fn compressed_response_is_dated_and_unsized() {
+        let text = "lightron ".repeat(1000);
+        let resource = testing::resource(&[("a.txt", text.as_bytes())]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\ncompression = true\n", resource)));
+        let (status, headers, body) = get(website, "http://localhost/a.txt", &[("accept-encoding", "gzip")]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.contains_key("date"));
+        assert!(headers.contains_key("last-modified"));
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert!(!headers.contains_key("content-length"));
+        assert!(!body.is_empty() && body.len() < text.len());
+    }
+
+    #[tokio::test]
+    async fn missing_push_file_still_answers_the_page() {
+        let resource = testing::resource(&[("index.html", b"<h1>hi</h1>"), ("a.css", b"h1{}")]);
+        let website = testing::leak(testing::website(&format!("resource = {:?}\npush_protocol_files = [\"missing.css\", \"a.css\"]\n", resource)));
+        let (status, _, body) = get(website, "http://localhost/", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "<h1>hi</h1>");
+    }
+}
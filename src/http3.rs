@@ -0,0 +1,290 @@
+use std::convert::TryFrom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use bytes::{Buf,Bytes};
+use http::{Method,Request,Response,StatusCode,Version};
+use http::header::{HeaderValue,ALT_SVC};
+use http::response::Builder;
+use h3::server::RequestStream;
+use h3_quinn::BidiStream;
+use rustls23::crypto::ring::{self as ring_provider, sign::any_supported_type};
+use rustls23::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls23::server::ResolvesServerCertUsingSni;
+use rustls23::sign::CertifiedKey;
+use quinn::crypto::rustls::QuicServerConfig;
+use log::{info,warn,error,trace};
+use crate::{DomainMap,Website};
+use crate::keys::{load_certs,load_private_key};
+use crate::dispatch;
+use crate::error_pages;
+use crate::locations;
+use crate::web_docs;
+use crate::body::Body;
+
+/// Advertises the website's HTTP/3 listener, on the same port over UDP, on a
+/// response sent over TLS.
+pub fn advertise(response: &mut Response<()>, site: &Website) {
+    if site.http3 && site.class == "HTTPS" {
+        response.headers_mut().insert(ALT_SVC, HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", site.port_no)).unwrap());
+    }
+}
+
+fn cert_chain(site: &Website) -> Vec<CertificateDer<'static>> {
+    load_certs(&site.certificate).into_iter().map(|cert| CertificateDer::from(cert.0)).collect()
+}
+
+fn private_key(site: &Website) -> PrivateKeyDer<'static> {
+    PrivateKeyDer::try_from(load_private_key(site).0.as_slice()).unwrap().clone_key()
+}
+
+#[tokio::main]
+pub async fn handle_http3(website: Website, is_virtually_shared : bool, domain_map : Option<DomainMap>) -> io::Result<()> {
+    let website: &'static Website = Box::leak(Box::new(website));
+    let port_no = website.port_no;
+    info!("Thread created for HTTP/3 port no : {}",port_no);
+    let addr: SocketAddr = if website.access == "Local" {
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),port_no)
+    }
+    else {
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),port_no)
+    };
+    let config = server_config(website, is_virtually_shared, domain_map.as_ref());
+    let domain_map: Option<&'static DomainMap> = domain_map.map(|domain_map| &*Box::leak(Box::new(domain_map)));
+    let endpoint = quinn::Endpoint::server(config, addr).unwrap();
+    accept(endpoint, website, domain_map).await;
+    Ok(())
+}
+
+/// QUIC settings of the port, with the certificate of each of its websites
+/// on HTTP/3 when it's shared.
+fn server_config(website: &Website, is_virtually_shared : bool, domain_map : Option<&DomainMap>) -> quinn::ServerConfig {
+    // QUIC only runs over TLS 1.3.
+    let builder = rustls23::ServerConfig::builder_with_provider(Arc::new(ring_provider::default_provider()))
+        .with_protocol_versions(&[&rustls23::version::TLS13]).unwrap()
+        .with_no_client_auth();
+    let mut config = if is_virtually_shared {
+        let mut resolver = ResolvesServerCertUsingSni::new();
+        for (domain_name,site) in domain_map.unwrap().iter().filter(|(_,site)| site.http3) {
+            resolver.add(domain_name, CertifiedKey::new(cert_chain(site), any_supported_type(&private_key(site)).unwrap())).unwrap();
+        }
+        builder.with_cert_resolver(Arc::new(resolver))
+    }
+    else {
+        builder.with_single_cert(cert_chain(website), private_key(website)).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err)).unwrap()
+    };
+    config.alpn_protocols = vec![b"h3".to_vec()];
+    quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(config).unwrap()))
+}
+
+/// Serves each connection `endpoint` accepts on a task of its own.
+async fn accept(endpoint: quinn::Endpoint, website: &'static Website, domain_map: Option<&'static DomainMap>) {
+    let port_no = website.port_no;
+    while let Some(incoming) = endpoint.accept().await {
+        let fut = async move {
+            let connection = incoming.await?;
+            let peer_addr = connection.remote_address();
+            info!("{} HTTP/3 Hello: {}", port_no ,peer_addr);
+            serve_connection(connection, website, domain_map, peer_addr).await
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = fut.await {
+                error!("{} {:?}", port_no,err);
+            }
+        });
+    }
+}
+
+/// Serves the HTTP/3 requests of a QUIC connection.
+async fn serve_connection(connection: quinn::Connection, website: &'static Website, domain_map: Option<&'static DomainMap>, peer_addr: SocketAddr) -> io::Result<()> {
+    let port_no = website.port_no;
+    let mut connection: h3::server::Connection<_, Bytes> = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await.map_err(io::Error::other)?;
+    loop {
+        match connection.accept().await {
+            Ok(Some(resolver)) => {
+                tokio::spawn(async move {
+                    let result = match resolver.resolve_request().await {
+                        Ok((request, stream)) => handle_request(request, stream, website, domain_map, peer_addr).await,
+                        Err(err) => Err(io::Error::other(err)),
+                    };
+                    if let Err(err) = result {
+                        error!("{} {:?}", port_no,err);
+                    }
+                });
+            },
+            Ok(None) => return Ok(()),
+            // A client closing the connection once it's done.
+            Err(err) if err.is_h3_no_error() => return Ok(()),
+            Err(err) => return Err(io::Error::other(err)),
+        }
+    }
+}
+
+/// The request as the rest of the server knows it, h3 having its own
+/// version of the `http` types.
+fn from_h3(request: http1::Request<()>) -> Option<Request<()>> {
+    let mut builder = Request::builder().method(request.method().as_str()).uri(request.uri().to_string()).version(Version::HTTP_3);
+    for (name, value) in request.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder.body(()).ok()
+}
+
+fn into_h3(response: Response<()>) -> http1::Response<()> {
+    let mut builder = http1::Response::builder().status(response.status().as_u16());
+    for (name, value) in response.headers() {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    builder.body(()).unwrap()
+}
+
+async fn handle_request(request: http1::Request<()>, mut stream: RequestStream<BidiStream<Bytes>, Bytes>, website: &Website, domain_map : Option<&DomainMap>, peer_addr: SocketAddr) -> io::Result<()> {
+    let port_no = website.port_no;
+    trace!("{} REQUEST : {:?}", port_no ,request);
+    let request = match from_h3(request) {
+        Some(request) => request,
+        None => {
+            warn!("{} 400 Bad request from {}",port_no,peer_addr);
+            let (response,contents) = error_pages::response(website, StatusCode::BAD_REQUEST).await;
+            return send_response(stream, response, contents, website, false).await;
+        }
+    };
+    // Websites sharing the port without HTTP/3 are left to their own
+    // listener, a client reusing the connection has to go there instead.
+    if let Some(site) = web_docs::site_for(website, domain_map, dispatch::host(&request)).filter(|site| !site.http3) {
+        warn!("{} 421 {} is not on HTTP/3",port_no,site.name);
+        let (response,contents) = error_pages::response(site, StatusCode::MISDIRECTED_REQUEST).await;
+        return send_response(stream, response, contents, site, false).await;
+    }
+    let (site,response,contents) = dispatch::dispatch(website, domain_map, &request, peer_addr, "https", |limit| dispatch::read_body(&mut stream, limit)).await?;
+    send_response(stream, response, contents, site, request.method() == Method::HEAD).await
+}
+
+impl dispatch::RequestBody for &mut RequestStream<BidiStream<Bytes>, Bytes> {
+    async fn next_data(&mut self) -> io::Result<Option<Bytes>> {
+        let data = self.recv_data().await.map_err(io::Error::other)?;
+        Ok(data.map(|mut data| data.copy_to_bytes(data.remaining())))
+    }
+}
+
+/// Answers on the request's stream, leaving out the body for HEAD requests,
+/// then finishes the stream.
+async fn send_response(mut stream: RequestStream<BidiStream<Bytes>, Bytes>, response: Builder, mut contents: Body, site: &Website, head_only: bool) -> io::Result<()> {
+    let mut response = response.version(Version::HTTP_3).header("Server", "Lightron/0.1.0").body(()).unwrap();
+    locations::finish(&mut response, site);
+    stream.send_response(into_h3(response)).await.map_err(io::Error::other)?;
+    if !head_only {
+        while let Some(chunk) = contents.next_chunk().await? {
+            stream.send_data(chunk).await.map_err(io::Error::other)?;
+        }
+    }
+    stream.finish().await.map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use http::HeaderMap;
+    use rustls23::RootCertStore;
+    use bytes::BytesMut;
+
+    /// A website on HTTP/3 with a certificate of its own for `localhost`.
+    fn website(files: &[(&str, &[u8])], settings: &str) -> (&'static Website, CertificateDer<'static>) {
+        let resource = testing::resource(files);
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (certificate, private_key) = (format!("{}.crt", resource), format!("{}.key", resource));
+        std::fs::write(&certificate, certified.cert.pem()).unwrap();
+        std::fs::write(&private_key, certified.key_pair.serialize_pem()).unwrap();
+        let website = testing::website(&format!("class = \"HTTPS\"\nhttp3 = true\nresource = {:?}\ncertificate = {:?}\nprivate_key = {:?}\n{}", resource, certificate, private_key, settings));
+        (testing::leak(website), certified.cert.der().clone())
+    }
+
+    /// Sends a request to `website` over QUIC on the loopback, answering the
+    /// status, headers and body.
+    async fn request(website: &'static Website, certificate: CertificateDer<'static>, method: &str, path: &str) -> (StatusCode, HeaderMap, Bytes) {
+        request_on(website, None, certificate, method, "localhost", path).await
+    }
+
+    /// Sends a request for `host` to the port of `website`, shared with the
+    /// websites of `domain_map` if any, over a connection to `localhost`.
+    async fn request_on(website: &'static Website, domain_map: Option<&'static DomainMap>, certificate: CertificateDer<'static>, method: &str, host: &str, path: &str) -> (StatusCode, HeaderMap, Bytes) {
+        let server = quinn::Endpoint::server(server_config(website, domain_map.is_some(), domain_map), "127.0.0.1:0".parse().unwrap()).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(accept(server, website, domain_map));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate).unwrap();
+        let mut tls = rustls23::ClientConfig::builder_with_provider(Arc::new(ring_provider::default_provider()))
+            .with_protocol_versions(&[&rustls23::version::TLS13]).unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls.alpn_protocols = vec![b"h3".to_vec()];
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(quinn::crypto::rustls::QuicClientConfig::try_from(tls).unwrap())));
+        let connection = client.connect(server_addr, "localhost").unwrap().await.unwrap();
+        let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection)).await.unwrap();
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+
+        let request = http1::Request::builder().method(method).uri(format!("https://{}:{}{}", host, server_addr.port(), path)).body(()).unwrap();
+        let mut stream = send_request.send_request(request).await.unwrap();
+        stream.finish().await.unwrap();
+        let response = stream.recv_response().await.unwrap();
+        let mut headers = HeaderMap::new();
+        for (name, value) in response.headers() {
+            headers.append(http::header::HeaderName::from_bytes(name.as_str().as_bytes()).unwrap(), HeaderValue::from_bytes(value.as_bytes()).unwrap());
+        }
+        let mut body = BytesMut::new();
+        while let Some(mut data) = stream.recv_data().await.unwrap() {
+            body.extend_from_slice(&data.copy_to_bytes(data.remaining()));
+        }
+        (StatusCode::from_u16(response.status().as_u16()).unwrap(), headers, body.freeze())
+    }
+
+    #[tokio::test]
+    async fn file_is_served_over_quic() {
+        let (website, certificate) = website(&[("a.txt", b"hello")], "");
+        let (status, headers, body) = request(website, certificate, "GET", "/a.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(httpdate::parse_http_date(headers["date"].to_str().unwrap()).is_ok());
+        assert_eq!(headers["content-length"], "5");
+        assert!(headers.contains_key("last-modified"));
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_not_found() {
+        let (website, certificate) = website(&[], "");
+        let (status, headers, body) = request(website, certificate, "GET", "/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(headers["content-length"], body.len().to_string().as_str());
+    }
+
+    #[tokio::test]
+    async fn head_has_no_body() {
+        let (website, certificate) = website(&[("a.txt", b"hello")], "");
+        let (status, headers, body) = request(website, certificate, "HEAD", "/a.txt").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["content-length"], "5");
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disallowed_method_is_answered_405() {
+        let (website, certificate) = website(&[], "methods = [\"GET\"]\n");
+        let (status, headers, _) = request(website, certificate, "DELETE", "/a.txt").await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert!(headers.contains_key("allow"));
+    }
+
+    #[tokio::test]
+    async fn website_without_http3_is_misdirected() {
+        let (website, certificate) = website(&[("a.txt", b"hello")], "");
+        // No certificate either, the port must not load one for it.
+        let other = testing::leak(testing::website("name = \"other.test\"\nclass = \"HTTPS\"\n"));
+        let domain_map: &'static DomainMap = Box::leak(Box::new(DomainMap::from([("localhost", website), ("other.test", other)])));
+        assert_eq!(request_on(website, Some(domain_map), certificate.clone(), "GET", "localhost", "/a.txt").await.0, StatusCode::OK);
+        assert_eq!(request_on(website, Some(domain_map), certificate, "GET", "other.test", "/a.txt").await.0, StatusCode::MISDIRECTED_REQUEST);
+    }
+}
@@ -0,0 +1,120 @@
+use http::header::{HeaderName, HeaderValue, CACHE_CONTROL};
+use http::Response;
+use regex::Regex;
+use crate::{paths, Location, Website};
+
+/// How a location matches request paths.
+#[derive(Debug, Clone)]
+enum Matcher {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// A location of a website, with the website as the location sees it.
+#[derive(Debug, Clone)]
+pub struct Route {
+    matcher: Matcher,
+    site: &'static Website,
+}
+
+/// Works out the settings of each of the website's locations once, before
+/// it is served.
+pub fn prepare(mut website: Website) -> Website {
+    let locations = std::mem::take(&mut website.locations);
+    website.routes = locations.iter().map(|location| {
+        let matcher = match (&location.exact, &location.prefix, &location.regex) {
+            (Some(path), _, _) => Matcher::Exact(path.clone()),
+            (None, Some(path), _) => Matcher::Prefix(path.clone()),
+            (None, None, Some(pattern)) => Matcher::Regex(Regex::new(pattern).unwrap_or_else(|err| panic!("invalid location regex {:?} : {}", pattern, err))),
+            (None, None, None) => panic!("location of {} without prefix, exact or regex", website.name),
+        };
+        let site: &'static Website = Box::leak(Box::new(merge(&website, location, &matcher)));
+        Route { matcher, site }
+    }).collect();
+    website
+}
+
+/// The website with the location's settings in place of its own.
+fn merge(website: &Website, location: &Location, matcher: &Matcher) -> Website {
+    let mut site = website.clone();
+    if let Some(proxy_pass) = &location.proxy_pass {
+        site.mode = "Proxy".to_string();
+        site.proxy_pass = proxy_pass.clone();
+    }
+    if let Some(root) = &location.root {
+        site.mode = "Static".to_string();
+        site.resource = root.clone();
+    }
+    if let Some(alias) = &location.alias {
+        site.mode = "Static".to_string();
+        site.resource = alias.clone();
+        site.alias_prefix = match matcher {
+            Matcher::Exact(path) | Matcher::Prefix(path) => Some(path.clone()),
+            Matcher::Regex(_) => panic!("alias {} needs a prefix or exact location", alias),
+        };
+    }
+    site.headers.extend(location.headers.clone());
+    if location.cache_control.is_some() {
+        site.cache_control = location.cache_control.clone();
+    }
+    if location.auth_realm.is_some() {
+        site.auth_realm = location.auth_realm.clone();
+    }
+    if let Some(auth_users) = &location.auth_users {
+        site.auth_users = auth_users.clone();
+    }
+    if let Some(methods) = &location.methods {
+        site.methods = methods.clone();
+    }
+    site
+}
+
+/// The website as the first of its locations matching `path` sees it, or the
+/// website itself when none does. Paths are matched once normalized.
+pub fn select<'a>(site: &'a Website, path: &str) -> &'a Website {
+    if site.routes.is_empty() {
+        return site;
+    }
+    let path = match paths::normalize(path) {
+        Ok(path) => path,
+        // Requests never get here with such a path, dispatch turns them away.
+        Err(_) => return site,
+    };
+    site.routes.iter()
+        .find(|route| match &route.matcher {
+            Matcher::Exact(exact) => path == *exact,
+            Matcher::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            Matcher::Regex(regex) => regex.is_match(&path),
+        })
+        .map(|route| route.site)
+        .unwrap_or(site)
+}
+
+/// The path under `resource` a normalized request path stands for, without
+/// the location prefix an alias replaces.
+pub fn file_path(site: &Website, uri_path: &str) -> String {
+    match site.alias_prefix.as_ref().and_then(|prefix| uri_path.strip_prefix(prefix.as_str())) {
+        Some(rest) => format!("/{}", rest.trim_start_matches('/')),
+        None => uri_path.to_string(),
+    }
+}
+
+/// Adds the website's headers to a response, and its `cache_control` to
+/// successful and redirect responses that don't carry their own.
+pub fn finish(response: &mut Response<()>, site: &Website) {
+    let status = response.status();
+    let headers = response.headers_mut();
+    for (name, value) in &site.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            headers.insert(name, value);
+        }
+    }
+    if let Some(cache_control) = &site.cache_control {
+        if (status.is_success() || status.is_redirection()) && !headers.contains_key(CACHE_CONTROL) {
+            if let Ok(value) = HeaderValue::from_str(cache_control) {
+                headers.insert(CACHE_CONTROL, value);
+            }
+        }
+    }
+}
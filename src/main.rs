@@ -1,12 +1,34 @@
 mod http2;
 mod http1_1;
+mod methods;
+mod ranges;
+mod conditional;
+mod body;
+mod compression;
+mod web_docs;
+mod autoindex;
+mod error_pages;
+mod paths;
+mod proxy;
+mod upstreams;
+mod locations;
+mod auth;
+mod rewrites;
+mod h2c;
+mod dispatch;
+mod http3;
+mod keys;
+#[cfg(test)]
+mod testing;
 use serde_derive::{Deserialize,Serialize};
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use crossbeam_utils::thread;
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use toml::from_str;
 use http2::handle_http2;
+use http3::handle_http3;
 use http1_1::handle_http1_1;
 use simplelog::*;
 use std::str::FromStr;
@@ -28,52 +50,258 @@ pub struct Website {
     resource: String,
     certificate: String,
     private_key: String,
+    #[serde(default)]
+    private_key_passphrase_file: Option<String>,
+    #[serde(default)]
+    private_key_passphrase_env: Option<String>,
+    #[serde(default)]
+    private_key_passphrase_credential: Option<String>,
     port_no: u16,
     push_protocol_files: Vec<String>,
-    log_level: String
+    log_level: String,
+    #[serde(default = "default_keep_alive_timeout")]
+    keep_alive_timeout: u64,
+    #[serde(default)]
+    h2c: bool,
+    #[serde(default)]
+    http3: bool,
+    #[serde(default = "default_max_header_size")]
+    max_header_size: usize,
+    #[serde(default = "default_max_header_count")]
+    max_header_count: usize,
+    #[serde(default)]
+    compression: bool,
+    #[serde(default)]
+    compression_level: Option<i32>,
+    #[serde(default = "default_compression_min_size")]
+    compression_min_size: u64,
+    #[serde(default = "default_index_files")]
+    index_files: Vec<String>,
+    #[serde(default)]
+    autoindex: bool,
+    #[serde(default)]
+    autoindex_show_hidden: bool,
+    #[serde(default)]
+    try_files: Vec<String>,
+    #[serde(default)]
+    error_pages: HashMap<String,String>,
+    #[serde(default = "default_max_body_size")]
+    max_body_size: u64,
+    #[serde(default = "default_mode")]
+    mode: String,
+    #[serde(default)]
+    proxy_pass: Vec<String>,
+    #[serde(default = "default_proxy_timeout")]
+    proxy_timeout: u64,
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    headers: HashMap<String,String>,
+    #[serde(default)]
+    cache_control: Option<String>,
+    #[serde(default)]
+    auth_realm: Option<String>,
+    #[serde(default)]
+    auth_users: HashMap<String,String>,
+    #[serde(default)]
+    locations: Vec<Location>,
+    #[serde(default)]
+    rewrites: Vec<Rewrite>,
+    #[serde(skip)]
+    rules: Vec<rewrites::Rule>,
+    /// The location prefix `resource` stands for, for locations with an
+    /// alias.
+    #[serde(skip)]
+    alias_prefix: Option<String>,
+    #[serde(skip)]
+    routes: Vec<locations::Route>
 }
 
+/// A rule rewriting the paths matching `pattern` to `replacement`, inside the
+/// server or, with `redirect` set to its status, by redirecting the client.
+/// `host` and `query` are patterns the request has to match as well.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Rewrite {
+    pattern: String,
+    replacement: String,
+    #[serde(default)]
+    redirect: Option<u16>,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    scheme: Option<String>,
+    #[serde(default)]
+    query: Option<String>
+}
+
+/// Paths of a website with settings of their own, matched by one of
+/// `prefix`, `exact` or `regex`. What a location leaves out is the website's.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Location {
+    #[serde(default)]
+    prefix: Option<String>,
+    #[serde(default)]
+    exact: Option<String>,
+    #[serde(default)]
+    regex: Option<String>,
+    #[serde(default)]
+    root: Option<String>,
+    #[serde(default)]
+    alias: Option<String>,
+    #[serde(default)]
+    proxy_pass: Option<Vec<String>>,
+    #[serde(default)]
+    headers: HashMap<String,String>,
+    #[serde(default)]
+    cache_control: Option<String>,
+    #[serde(default)]
+    auth_realm: Option<String>,
+    #[serde(default)]
+    auth_users: Option<HashMap<String,String>>,
+    #[serde(default)]
+    methods: Option<Vec<String>>
+}
+
+/// A named set of servers proxied websites balance their requests over, by
+/// listing its name in `proxy_pass`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UpstreamGroup {
+    name: String,
+    servers: Vec<String>,
+    #[serde(default = "default_strategy")]
+    strategy: String,
+    #[serde(default = "default_max_fails")]
+    max_fails: u32,
+    #[serde(default = "default_fail_timeout")]
+    fail_timeout: u64,
+    #[serde(default)]
+    health_check: Option<String>,
+    #[serde(default = "default_health_check_interval")]
+    health_check_interval: u64
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Config {
+    websites: Vec<Website>,
+    #[serde(default)]
+    upstreams: Vec<UpstreamGroup>
+}
+
+/// Websites sharing a port, by domain name.
+pub type DomainMap = HashMap<&'static str,&'static Website>;
+
+fn default_keep_alive_timeout() -> u64 {
+    5
+}
+
+fn default_max_header_size() -> usize {
+    8192
+}
+
+fn default_max_header_count() -> usize {
+    100
+}
+
+fn default_compression_min_size() -> u64 {
+    1024
+}
+
+fn default_max_body_size() -> u64 {
+    1024 * 1024
+}
+
+fn default_mode() -> String {
+    "Static".to_string()
+}
+
+fn default_proxy_timeout() -> u64 {
+    60
+}
+
+fn default_strategy() -> String {
+    "RoundRobin".to_string()
+}
+
+fn default_max_fails() -> u32 {
+    3
+}
+
+fn default_fail_timeout() -> u64 {
+    10
+}
+
+fn default_health_check_interval() -> u64 {
+    5
+}
+
+fn default_index_files() -> Vec<String> {
+    vec!["index.html".to_string(), "index.htm".to_string(), "default.html".to_string()]
+}
+
+
+/// `lightron --test-rewrite URL` prints how the rewrite rules of the
+/// website serving `URL` treat it, instead of starting the server.
+fn test_rewrite() -> bool {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 || args[1] != "--test-rewrite" {
+        return false;
+    }
+    let config: Config = from_str(&read_conf()).unwrap();
+    let websites: Vec<Website> = config.websites.iter().cloned().map(rewrites::prepare).collect();
+    rewrites::test(&websites, &args[2]);
+    true
+}
 
 #[cfg(windows)]
 fn main() -> windows_service::Result<()> {
+    if test_rewrite() {
+        return Ok(());
+    }
     lightron_service::run()
 }
 
 
 #[cfg(not(windows))]
 fn main() {
-    let config: HashMap<String, Vec<Website>> = from_str(&read_conf()).unwrap();
-    let mut virtual_hosted_website : HashMap<u16,HashMap<&'static str,[&'static str;3]>> = HashMap::new(); // domain_name : <resourcepath,certificate,privkey>
-    let mut websites = config["websites"].clone();
+    if test_rewrite() {
+        return;
+    }
+    let config: Config = from_str(&read_conf()).unwrap();
+    let mut virtual_hosted_website : HashMap<u16,DomainMap> = HashMap::new(); // port_no : <domain_name : website>
+    let mut websites: Vec<Website> = config.websites.iter().cloned().map(rewrites::prepare).map(locations::prepare).collect();
     for website in &websites {
-        let mut v_websites : HashMap<&'static str,[&'static str;3]> = HashMap::new();
-        if !virtual_hosted_website.contains_key(&website.port_no) {
+        let mut v_websites : DomainMap = HashMap::new();
+        if let Entry::Vacant(entry) = virtual_hosted_website.entry(website.port_no) {
             for webs in websites.clone() {
                 if website.port_no == webs.port_no {
-                    v_websites.insert(Box::leak(webs.name.into_boxed_str()),[Box::leak(webs.resource.into_boxed_str()),Box::leak(webs.certificate.into_boxed_str()),Box::leak(webs.private_key.into_boxed_str())]);
+                    v_websites.insert(Box::leak(webs.name.clone().into_boxed_str()),Box::leak(Box::new(webs)));
                 }
             }
-            virtual_hosted_website.insert(website.port_no,v_websites.clone());
+            entry.insert(v_websites.clone());
         }
     }
     for port in virtual_hosted_website.keys() {
-        let mut websites_to_be_removed : Vec<u8> = Vec::new();
-        let mut i = 0;
-        for website in &websites {
+        let mut websites_to_be_removed : Vec<usize> = Vec::new();
+        for (i, website) in websites.iter().enumerate() {
             if website.port_no == *port {
                 websites_to_be_removed.push(i);
             }
-            i+=1;
         }
         websites_to_be_removed.remove(0);
         for j in websites_to_be_removed {
-            websites.remove(j.into());
+            websites.remove(j);
         }
     }
     let mut log_config = ConfigBuilder::new();
     log_config.set_time_to_local(true);
-    WriteLogger::init(LevelFilter::from_str(&websites[0].log_level).unwrap(), log_config.build(), std::fs::File::create(format!("lightron.log")).unwrap()).unwrap();
+    WriteLogger::init(LevelFilter::from_str(&websites[0].log_level).unwrap(), log_config.build(), std::fs::File::create("lightron.log").unwrap()).unwrap();
+    upstreams::init(config.upstreams.clone());
     thread::scope(|s| {
+        if upstreams::has_health_checks() {
+            s.builder().name("health checks".to_string()).spawn(|_| {
+                upstreams::health_checks();
+            }).unwrap();
+        }
         for website in websites.clone() {
             let (is_virtually_shared,domain_map) = if virtual_hosted_website[&website.port_no].len() == 1 {
                 (false,None)
@@ -82,13 +310,19 @@ fn main() {
                 (true,Some(virtual_hosted_website[&website.port_no].clone()))
             };
             if website.class == "HTTPS" {
+                if website.http3 || domain_map.iter().flat_map(|domain_map| domain_map.values()).any(|site| site.http3) {
+                    let (website,domain_map) = (website.clone(),domain_map.clone());
+                    s.builder().name(format!("{}/udp",website.port_no)).spawn(move |_| {
+                        handle_http3(website,is_virtually_shared,domain_map).unwrap();
+                    }).unwrap();
+                }
                 s.builder().name(website.port_no.to_string()).spawn(move |_| {
-                    handle_http2(&website.access,website.port_no,&website.certificate,&website.private_key,Box::leak(website.resource.into_boxed_str()),website.push_protocol_files,is_virtually_shared,domain_map).unwrap();
+                    handle_http2(website,is_virtually_shared,domain_map).unwrap();
                 }).unwrap();
             }
             else {
                 s.builder().name(website.port_no.to_string()).spawn(move |_| {
-                    handle_http1_1(&website.access,website.port_no,Box::leak(website.resource.into_boxed_str()),is_virtually_shared,domain_map).unwrap();
+                    handle_http1_1(website,domain_map).unwrap();
                 }).unwrap();
             }
         }
@@ -150,38 +384,42 @@ mod lightron_service {
             wait_hint: Duration::default(),
             process_id: None,
         })?;
-        let config: HashMap<String, Vec<Website>> = from_str(&read_conf()).unwrap();
-        let mut virtual_hosted_website : HashMap<u16,HashMap<&'static str,[&'static str;3]>> = HashMap::new(); // domain_name : <resourcepath>
-        let mut websites = config["websites"].clone();
+        let config: Config = from_str(&read_conf()).unwrap();
+        let mut virtual_hosted_website : HashMap<u16,DomainMap> = HashMap::new(); // port_no : <domain_name : website>
+        let mut websites: Vec<Website> = config.websites.iter().cloned().map(rewrites::prepare).map(locations::prepare).collect();
         for website in &websites {
-            let mut v_websites : HashMap<&'static str,[&'static str;3]> = HashMap::new();
-            if !virtual_hosted_website.contains_key(&website.port_no) {
+            let mut v_websites : DomainMap = HashMap::new();
+            if let Entry::Vacant(entry) = virtual_hosted_website.entry(website.port_no) {
                 for webs in websites.clone() {
                     if website.port_no == webs.port_no {
-                        v_websites.insert(Box::leak(webs.name.into_boxed_str()),[Box::leak(webs.resource.into_boxed_str()),Box::leak(webs.certificate.into_boxed_str()),Box::leak(webs.private_key.into_boxed_str())]);
+                        v_websites.insert(Box::leak(webs.name.clone().into_boxed_str()),Box::leak(Box::new(webs)));
                     }
                 }
-                virtual_hosted_website.insert(website.port_no,v_websites.clone());
+                entry.insert(v_websites.clone());
             }
         }
         for port in virtual_hosted_website.keys() {
-            let mut websites_to_be_removed : Vec<u8> = Vec::new();
-            let mut i = 0;
-            for website in &websites {
+            let mut websites_to_be_removed : Vec<usize> = Vec::new();
+            for (i, website) in websites.iter().enumerate() {
                 if website.port_no == *port {
                     websites_to_be_removed.push(i);
                 }
-                i+=1;
             }
             websites_to_be_removed.remove(0);
             for j in websites_to_be_removed {
-                websites.remove(j.into());
+                websites.remove(j);
             }
         }
         let mut log_config = ConfigBuilder::new();
         log_config.set_time_to_local(true);
-        WriteLogger::init(LevelFilter::from_str(&websites[0].log_level).unwrap(), log_config.build(), std::fs::File::create(format!("lightron.log")).unwrap()).unwrap();
+        WriteLogger::init(LevelFilter::from_str(&websites[0].log_level).unwrap(), log_config.build(), std::fs::File::create("lightron.log").unwrap()).unwrap();
+        upstreams::init(config.upstreams.clone());
         thread::scope(|s| {
+            if upstreams::has_health_checks() {
+                s.builder().name("health checks".to_string()).spawn(|_| {
+                    upstreams::health_checks();
+                }).unwrap();
+            }
             for website in websites.clone() {
                 let (is_virtually_shared,domain_map) = if virtual_hosted_website[&website.port_no].len() == 1 {
                     (false,None)
@@ -190,13 +428,19 @@ mod lightron_service {
                     (true,Some(virtual_hosted_website[&website.port_no].clone()))
                 };
                 if website.class == "HTTPS" {
+                    if website.http3 || domain_map.iter().flat_map(|domain_map| domain_map.values()).any(|site| site.http3) {
+                        let (website,domain_map) = (website.clone(),domain_map.clone());
+                        s.builder().name(format!("{}/udp",website.port_no)).spawn(move |_| {
+                            handle_http3(website,is_virtually_shared,domain_map).unwrap();
+                        }).unwrap();
+                    }
                     s.builder().name(website.port_no.to_string()).spawn(move |_| {
-                        handle_http2(&website.access,website.port_no,&website.certificate,&website.private_key,Box::leak(website.resource.into_boxed_str()),website.push_protocol_files,is_virtually_shared,domain_map).unwrap();
+                        handle_http2(website,is_virtually_shared,domain_map).unwrap();
                     }).unwrap();
                 }
                 else {
                     s.builder().name(website.port_no.to_string()).spawn(move |_| {
-                        handle_http1_1(&website.access,website.port_no,Box::leak(website.resource.into_boxed_str()),is_virtually_shared,domain_map).unwrap();
+                        handle_http1_1(website,domain_map).unwrap();
                     }).unwrap();
                 }
             }
@@ -0,0 +1,28 @@
+use http::Method;
+use crate::proxy;
+use crate::Website;
+
+/// Whether the website answers `method`. Static websites only answer methods
+/// that read a resource, proxied ones pass any on, and a website's `methods`
+/// narrows either down. OPTIONS is always answered.
+pub fn is_allowed(site: &Website, method: &Method) -> bool {
+    if method == Method::OPTIONS {
+        return true;
+    }
+    // HEAD goes with GET.
+    let listed = site.methods.is_empty() || site.methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method.as_str()) || (method == Method::HEAD && allowed.eq_ignore_ascii_case("GET")));
+    listed && (proxy::is_proxied(site) || method == Method::GET || method == Method::HEAD)
+}
+
+/// Value of the `Allow` header sent with 405 Method Not Allowed and OPTIONS.
+pub fn allow(site: &Website) -> String {
+    let mut allowed: Vec<String> = if proxy::is_proxied(site) {
+        site.methods.iter().map(|method| method.to_ascii_uppercase()).collect()
+    }
+    else {
+        [Method::GET, Method::HEAD].iter().filter(|method| is_allowed(site, method)).map(|method| method.to_string()).collect()
+    };
+    allowed.retain(|method| method != "OPTIONS");
+    allowed.push("OPTIONS".to_string());
+    allowed.join(", ")
+}
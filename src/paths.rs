@@ -0,0 +1,147 @@
+use http::StatusCode;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+/// Turns the path of a request target into the path looked up in a website:
+/// the query and fragment are stripped, escapes decoded, `.` and `..`
+/// segments removed and repeated slashes collapsed. A trailing slash is kept,
+/// it tells a directory listing or index apart from a redirect.
+///
+/// Escapes that don't decode to UTF-8 are a bad request. A path whose `..`
+/// segments climb above the root, that smuggles in a null byte, or that uses
+/// overlong UTF-8 forms is forbidden rather than clamped to the root or
+/// rejected as malformed, so the attempt gets noticed.
+pub fn normalize(target: &str) -> Result<String, StatusCode> {
+    let path = target.split(['?', '#']).next().unwrap_or("");
+    let decoded = match percent_decode_str(path).decode_utf8() {
+        Ok(decoded) => decoded,
+        Err(_) if has_overlong(&percent_decode_str(path).collect::<Vec<u8>>()) => return Err(StatusCode::FORBIDDEN),
+        Err(_) => return Err(StatusCode::BAD_REQUEST),
+    };
+    if decoded.contains('\0') {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+    for segment in decoded.split('/') {
+        trailing_slash = false;
+        match segment {
+            "" => trailing_slash = true,
+            "." => trailing_slash = true,
+            ".." => {
+                segments.pop().ok_or(StatusCode::FORBIDDEN)?;
+                trailing_slash = true;
+            },
+            _ => segments.push(segment),
+        }
+    }
+    let mut normalized = String::with_capacity(decoded.len());
+    for segment in &segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if trailing_slash || segments.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Whether `bytes` hold an overlong UTF-8 form, such as `%c0%ae` for `.`,
+/// which decoders that accept them turn into traversals.
+fn has_overlong(bytes: &[u8]) -> bool {
+    bytes.iter().enumerate().any(|(i, &byte)| {
+        let next = bytes.get(i + 1).copied().unwrap_or(0);
+        matches!(byte, 0xc0 | 0xc1) || (byte == 0xe0 && (0x80..0xa0).contains(&next)) || (byte == 0xf0 && (0x80..0x90).contains(&next))
+    })
+}
+
+/// Characters escaped when a path goes back into a URI, besides controls.
+const PATH: &AsciiSet = &CONTROLS.add(b' ').add(b'"').add(b'#').add(b'%').add(b'<').add(b'>').add(b'?').add(b'`').add(b'{').add(b'}');
+
+/// Escapes a normalized path so it can be sent in a `Location` header or a
+/// link again.
+pub fn encode(path: &str) -> String {
+    utf8_percent_encode(path, PATH).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_slashes_dont_smuggle_dot_dot_past_the_root() {
+        assert_eq!(normalize("/a/..%2F..%2Fetc/passwd"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/a/..%2f..%2fetc/passwd"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/a/b/..%2Fc"), Ok("/a/c".to_string()));
+        assert_eq!(normalize("/a%2Fb"), Ok("/a/b".to_string()));
+    }
+
+    #[test]
+    fn repeated_slashes_collapse() {
+        assert_eq!(normalize("//a///b"), Ok("/a/b".to_string()));
+        assert_eq!(normalize("//"), Ok("/".to_string()));
+        assert_eq!(normalize("/a//"), Ok("/a/".to_string()));
+    }
+
+    #[test]
+    fn dot_segments_are_removed() {
+        assert_eq!(normalize("/a/./b/../c/"), Ok("/a/c/".to_string()));
+        assert_eq!(normalize("/a/%2e/b/%2E%2E/c"), Ok("/a/c".to_string()));
+        assert_eq!(normalize("/a/b/.."), Ok("/a/".to_string()));
+        assert_eq!(normalize("/a/..."), Ok("/a/...".to_string()));
+    }
+
+    #[test]
+    fn trailing_slash_is_kept() {
+        assert_eq!(normalize("/a/"), Ok("/a/".to_string()));
+        assert_eq!(normalize("/a"), Ok("/a".to_string()));
+        assert_eq!(normalize("/a/."), Ok("/a/".to_string()));
+        assert_eq!(normalize("/"), Ok("/".to_string()));
+        assert_eq!(normalize(""), Ok("/".to_string()));
+    }
+
+    #[test]
+    fn query_and_fragment_are_stripped() {
+        assert_eq!(normalize("/app.js?v=3"), Ok("/app.js".to_string()));
+        assert_eq!(normalize("/a#top"), Ok("/a".to_string()));
+        assert_eq!(normalize("/a/?q=/../..#x"), Ok("/a/".to_string()));
+        // Escaped, they are part of the name.
+        assert_eq!(normalize("/a%3Fb%23c"), Ok("/a?b#c".to_string()));
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(normalize("/my%20file.pdf"), Ok("/my file.pdf".to_string()));
+        assert_eq!(normalize("/caf%C3%A9"), Ok("/café".to_string()));
+        assert_eq!(normalize("/100%25"), Ok("/100%".to_string()));
+    }
+
+    #[test]
+    fn climbing_above_the_root_is_forbidden() {
+        assert_eq!(normalize("/.."), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/../a"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/a/../../b"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/%2e%2e/a"), Err(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn null_bytes_and_overlong_forms_are_forbidden() {
+        assert_eq!(normalize("/a%00b"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/%c0%ae%c0%ae/a"), Err(StatusCode::FORBIDDEN));
+        assert_eq!(normalize("/%e0%80%af"), Err(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn invalid_utf8_is_a_bad_request() {
+        assert_eq!(normalize("/%ff"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(normalize("/%C3"), Err(StatusCode::BAD_REQUEST));
+        assert_eq!(normalize("/%80"), Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn encode_escapes_what_a_uri_path_cannot_hold() {
+        assert_eq!(encode("/my file?.pdf"), "/my%20file%3F.pdf");
+        assert_eq!(encode("/100%#x"), "/100%25%23x");
+        assert_eq!(encode("/café"), "/caf%C3%A9");
+        assert_eq!(normalize(&encode("/a b/%c")), Ok("/a b/%c".to_string()));
+    }
+}
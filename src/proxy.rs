@@ -0,0 +1,553 @@
+use bytes::{Bytes, BytesMut};
+use http::header::{HeaderMap, HeaderName, CONNECTION, CONTENT_LENGTH, COOKIE, EXPECT, FORWARDED, HOST, TRANSFER_ENCODING};
+use http::response::Builder;
+use http::{Method, Request, Response, StatusCode};
+use log::{debug, warn};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::time::timeout;
+use crate::body::Body;
+use crate::upstreams::{self, Lease};
+use crate::Website;
+
+/// Largest response head accepted from an upstream.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Largest chunk read from an upstream at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Headers that only describe one connection and are never forwarded.
+const HOP_BY_HOP: [&str; 8] = ["connection", "keep-alive", "proxy-connection", "proxy-authenticate", "proxy-authorization", "te", "trailer", "upgrade"];
+
+/// Whether the website forwards its requests instead of serving its resource.
+pub fn is_proxied(site: &Website) -> bool {
+    site.mode == "Proxy"
+}
+
+/// A connection to an upstream, over TCP or a Unix socket.
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// An address a proxied website forwards requests to, `host:port` or
+/// `unix:/path/to/socket`.
+#[derive(Clone)]
+pub enum Upstream {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Upstream {
+    pub fn parse(address: &str) -> Upstream {
+        match address.strip_prefix("unix:") {
+            Some(path) => Upstream::Unix(PathBuf::from(path)),
+            None => Upstream::Tcp(address.to_string()),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Box<dyn Connection>> {
+        match self {
+            Upstream::Tcp(address) => {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            },
+            #[cfg(unix)]
+            Upstream::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Upstream::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are not supported on this platform")),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Upstream::Tcp(address) => write!(f, "{}", address),
+            Upstream::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Forwards `request`, whose body was read into `body`, to the first of the
+/// website's upstreams that accepts a connection, and starts the response
+/// with what it answers. Upstreams of a group come in the order its strategy
+/// picks them. The response body is streamed from the upstream as the client
+/// reads it. `proto` is the scheme the client used, `http` or `https`.
+///
+/// Errors are the status to answer with: 502 Bad Gateway when no upstream
+/// can be reached or it answers garbage, 504 Gateway Timeout when it doesn't
+/// answer in time.
+pub async fn forward<B>(site: &Website, request: &Request<B>, body: Bytes, peer_addr: SocketAddr, proto: &str) -> Result<(Builder, Body), StatusCode> {
+    let proxy_timeout = Duration::from_secs(site.proxy_timeout);
+    let head = request_head(request, &body, peer_addr, proto);
+    for candidate in upstreams::candidates(site, peer_addr.ip()) {
+        let upstream = candidate.upstream;
+        let lease = candidate.server.map(|server| server.lease());
+        let connection = match timeout(proxy_timeout, upstream.connect()).await {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(err)) => {
+                warn!("{} upstream {} : {}", site.port_no, upstream, err);
+                if let Some(server) = candidate.server {
+                    server.failed();
+                }
+                continue;
+            },
+            Err(_) => {
+                warn!("{} upstream {} : connect timed out", site.port_no, upstream);
+                if let Some(server) = candidate.server {
+                    server.failed();
+                }
+                continue;
+            },
+        };
+        debug!("{} {} {} -> {}", site.port_no, request.method(), request.uri(), upstream);
+        // Once sent, a request is not tried on another upstream, it may not
+        // be safe to repeat.
+        let response = match timeout(proxy_timeout, exchange(connection, &head, &body, request.method(), proxy_timeout, lease)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(err)) => {
+                warn!("{} upstream {} : {}", site.port_no, upstream, err);
+                Err(StatusCode::BAD_GATEWAY)
+            },
+            Err(_) => {
+                warn!("{} upstream {} : response timed out", site.port_no, upstream);
+                Err(StatusCode::GATEWAY_TIMEOUT)
+            },
+        };
+        if let Some(server) = candidate.server {
+            if response.is_ok() { server.succeeded() } else { server.failed() }
+        }
+        return response;
+    }
+    warn!("{} no upstream available", site.port_no);
+    Err(StatusCode::BAD_GATEWAY)
+}
+
+/// Requests `path` from `upstream` for a health check and answers the status
+/// of the response.
+pub async fn probe(upstream: &Upstream, path: &str) -> io::Result<StatusCode> {
+    let host = match upstream {
+        Upstream::Tcp(address) => address.as_str(),
+        Upstream::Unix(_) => "localhost",
+    };
+    let mut connection = upstream.connect().await?;
+    connection.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: Lightron health check\r\nConnection: close\r\n\r\n", path, host).as_bytes()).await?;
+    connection.flush().await?;
+    let mut reader = BufReader::new(connection);
+    let head = read_head(&mut reader).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 100];
+    let mut parsed = httparse::Response::new(&mut headers);
+    parsed.parse(&head).map_err(io::Error::other)?;
+    StatusCode::from_u16(parsed.code.unwrap_or(0)).map_err(io::Error::other)
+}
+
+/// Builds the HTTP/1.1 head of the request sent upstream. Hop-by-hop headers
+/// are dropped, the client's address and scheme are added to
+/// `X-Forwarded-For`, `X-Forwarded-Proto` and `Forwarded`.
+fn request_head<B>(request: &Request<B>, body: &Bytes, peer_addr: SocketAddr, proto: &str) -> Vec<u8> {
+    let target = request.uri().path_and_query().map(|path_and_query| path_and_query.as_str()).unwrap_or("/");
+    let host = request.headers().get(HOST).and_then(|host| host.to_str().ok()).map(|host| host.to_string())
+        .or_else(|| request.uri().authority().map(|authority| authority.to_string()))
+        .unwrap_or_default();
+    let connection_options = connection_options(request.headers());
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method(), target, host).into_bytes();
+    let mut forwarded_for = Vec::new();
+    let mut forwarded = Vec::new();
+    let mut cookies = Vec::new();
+    for (name, value) in request.headers() {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        match name.as_str() {
+            "x-forwarded-for" => forwarded_for.push(value),
+            "forwarded" => forwarded.push(value),
+            "x-forwarded-proto" => {},
+            // HTTP/2 clients may split cookies over several headers, HTTP/1.1
+            // wants them in one.
+            "cookie" => cookies.push(value),
+            _ if is_hop_by_hop(name, &connection_options) || name == HOST || name == CONTENT_LENGTH || name == TRANSFER_ENCODING || name == EXPECT => {},
+            _ => push_header(&mut head, name.as_str(), value),
+        }
+    }
+    if !cookies.is_empty() {
+        push_header(&mut head, COOKIE.as_str(), &cookies.join("; "));
+    }
+    let client = peer_addr.ip().to_string();
+    forwarded_for.push(&client);
+    push_header(&mut head, "x-forwarded-for", &forwarded_for.join(", "));
+    push_header(&mut head, "x-forwarded-proto", proto);
+    let node = if peer_addr.is_ipv6() { format!("\"[{}]\"", client) } else { client.clone() };
+    let element = format!("for={};proto={};host=\"{}\"", node, proto, host.replace('"', ""));
+    forwarded.push(&element);
+    push_header(&mut head, FORWARDED.as_str(), &forwarded.join(", "));
+    if !body.is_empty() || matches!(*request.method(), Method::POST | Method::PUT | Method::PATCH) {
+        push_header(&mut head, CONTENT_LENGTH.as_str(), &body.len().to_string());
+    }
+    // A connection per request, the response body ends with it at the latest.
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+    head
+}
+
+fn push_header(head: &mut Vec<u8>, name: &str, value: &str) {
+    head.extend_from_slice(name.as_bytes());
+    head.extend_from_slice(b": ");
+    head.extend_from_slice(value.as_bytes());
+    head.extend_from_slice(b"\r\n");
+}
+
+/// Header names listed in `Connection`, which are hop-by-hop as well.
+fn connection_options(headers: &HeaderMap) -> Vec<String> {
+    headers.get_all(CONNECTION).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &HeaderName, connection_options: &[String]) -> bool {
+    HOP_BY_HOP.contains(&name.as_str()) || connection_options.iter().any(|option| option == name.as_str())
+}
+
+/// Sends the request on `connection` and reads the head of the response.
+async fn exchange(mut connection: Box<dyn Connection>, head: &[u8], body: &Bytes, method: &Method, proxy_timeout: Duration, lease: Option<Lease>) -> io::Result<(Builder, Body)> {
+    connection.write_all(head).await?;
+    connection.write_all(body).await?;
+    connection.flush().await?;
+    let mut reader = BufReader::with_capacity(CHUNK_SIZE, connection);
+    loop {
+        let head = read_head(&mut reader).await?;
+        let mut headers = [httparse::EMPTY_HEADER; 100];
+        let mut parsed = httparse::Response::new(&mut headers);
+        if !parsed.parse(&head).map_err(io::Error::other)?.is_complete() {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let status = StatusCode::from_u16(parsed.code.unwrap_or(0)).map_err(io::Error::other)?;
+        // Interim responses are for the connection to the upstream only.
+        if status.is_informational() {
+            continue;
+        }
+        let mut upstream_headers = HeaderMap::new();
+        for header in parsed.headers.iter() {
+            let name = HeaderName::from_bytes(header.name.as_bytes()).map_err(io::Error::other)?;
+            upstream_headers.append(name, http::HeaderValue::from_bytes(header.value).map_err(io::Error::other)?);
+        }
+        let connection_options = connection_options(&upstream_headers);
+        let mut response = Response::builder().status(status);
+        for (name, value) in &upstream_headers {
+            if !is_hop_by_hop(name, &connection_options) && name != TRANSFER_ENCODING && name != CONTENT_LENGTH && name != "server" {
+                response = response.header(name, value);
+            }
+        }
+        let content_length = upstream_headers.get(CONTENT_LENGTH).and_then(|len| len.to_str().ok()).and_then(|len| len.trim().parse::<u64>().ok());
+        let chunked = upstream_headers.get_all(TRANSFER_ENCODING).iter().any(|coding| coding.to_str().map(|coding| coding.to_ascii_lowercase().contains("chunked")).unwrap_or(false));
+        // RFC 7230 section 3.3.3, in order.
+        let framing = if *method == Method::HEAD || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+            if let Some(len) = content_length {
+                response = response.header(CONTENT_LENGTH, len);
+            }
+            Framing::Length(0)
+        }
+        else if chunked {
+            Framing::Chunked(None)
+        }
+        else if let Some(len) = content_length {
+            response = response.header(CONTENT_LENGTH, len);
+            Framing::Length(len)
+        }
+        else {
+            Framing::Close
+        };
+        return Ok((response, Body::from_upstream(UpstreamBody { reader, framing, read_timeout: proxy_timeout, _lease: lease })));
+    }
+}
+
+/// Reads a response head up to and including the empty line that ends it.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<BytesMut> {
+    let mut head = BytesMut::new();
+    loop {
+        let mut line = Vec::new();
+        if (&mut *reader).take(MAX_HEAD_SIZE as u64).read_until(b'\n', &mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&line);
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "response head too large"));
+        }
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(head);
+        }
+    }
+}
+
+/// How the end of a response body is found.
+enum Framing {
+    /// This many bytes are left.
+    Length(u64),
+    /// Chunked, with the bytes left of the current chunk once its size was read.
+    Chunked(Option<u64>),
+    /// Everything until the upstream closes the connection.
+    Close,
+    Done,
+}
+
+/// The body of a response being read from an upstream.
+pub struct UpstreamBody {
+    reader: BufReader<Box<dyn Connection>>,
+    framing: Framing,
+    read_timeout: Duration,
+    /// Keeps the request counted against its server until the body is done.
+    _lease: Option<Lease>,
+}
+
+impl UpstreamBody {
+    /// Number of bytes left, when the upstream sent a `Content-Length`.
+    pub fn len(&self) -> Option<u64> {
+        match self.framing {
+            Framing::Length(len) => Some(len),
+            Framing::Done => Some(0),
+            _ => None,
+        }
+    }
+
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        loop {
+            match self.framing {
+                Framing::Length(0) | Framing::Done => return Ok(None),
+                Framing::Length(len) => {
+                    let chunk = self.read(len).await?;
+                    if chunk.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.framing = Framing::Length(len - chunk.len() as u64);
+                    return Ok(Some(chunk));
+                },
+                Framing::Close => {
+                    let chunk = self.read(CHUNK_SIZE as u64).await?;
+                    if chunk.is_empty() {
+                        self.framing = Framing::Done;
+                        return Ok(None);
+                    }
+                    return Ok(Some(chunk));
+                },
+                Framing::Chunked(None) => {
+                    let line = self.read_line().await?;
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid chunk size"))?;
+                    if size == 0 {
+                        // Trailers are not passed on.
+                        while !self.read_line().await?.is_empty() {}
+                        self.framing = Framing::Done;
+                        return Ok(None);
+                    }
+                    self.framing = Framing::Chunked(Some(size));
+                },
+                Framing::Chunked(Some(0)) => {
+                    if !self.read_line().await?.is_empty() {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "chunk not followed by CRLF"));
+                    }
+                    self.framing = Framing::Chunked(None);
+                },
+                Framing::Chunked(Some(left)) => {
+                    let chunk = self.read(left).await?;
+                    if chunk.is_empty() {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.framing = Framing::Chunked(Some(left - chunk.len() as u64));
+                    return Ok(Some(chunk));
+                },
+            }
+        }
+    }
+
+    /// Reads whatever is buffered or arrives next, at most `max` bytes.
+    async fn read(&mut self, max: u64) -> io::Result<Bytes> {
+        let buffered = match timeout(self.read_timeout, self.reader.fill_buf()).await {
+            Ok(buffered) => buffered?,
+            Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        let chunk = Bytes::copy_from_slice(&buffered[..buffered.len().min(max as usize)]);
+        self.reader.consume(chunk.len());
+        Ok(chunk)
+    }
+
+    async fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        match timeout(self.read_timeout, (&mut self.reader).take(4096).read_line(&mut line)).await {
+            Ok(Ok(0)) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(Ok(_)) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(io::ErrorKind::TimedOut.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use crate::testing;
+
+    fn head(request: Request<()>, peer_addr: &str) -> String {
+        String::from_utf8(request_head(&request, &Bytes::new(), peer_addr.parse().unwrap(), "https")).unwrap()
+    }
+
+    fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+        head.lines().find_map(|line| line.split_once(": ").filter(|(line_name, _)| line_name.eq_ignore_ascii_case(name)).map(|(_, value)| value))
+    }
+
+    #[test]
+    fn hop_by_hop_headers_are_not_forwarded() {
+        let request = Request::get("/a?b=c").header(HOST, "example.com")
+            .header(CONNECTION, "keep-alive, X-Custom").header("keep-alive", "timeout=5").header("te", "trailers")
+            .header("upgrade", "websocket").header("proxy-authorization", "Basic eA==").header("x-custom", "1")
+            .header("x-kept", "2").header(COOKIE, "a=1").header(COOKIE, "b=2")
+            .body(()).unwrap();
+        let head = head(request, "192.0.2.1:5000");
+        assert!(head.starts_with("GET /a?b=c HTTP/1.1\r\nHost: example.com\r\n"), "{}", head);
+        for name in ["keep-alive", "te", "upgrade", "proxy-authorization", "x-custom"] {
+            assert_eq!(header(&head, name), None, "{}", name);
+        }
+        assert_eq!(header(&head, "connection"), Some("close"));
+        assert_eq!(header(&head, "x-kept"), Some("2"));
+        assert_eq!(header(&head, "cookie"), Some("a=1; b=2"));
+        assert!(head.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn ipv4_client_is_added_to_forwarded_headers() {
+        let request = Request::get("/").header(HOST, "example.com").header("x-forwarded-for", "198.51.100.7")
+            .header("x-forwarded-proto", "http").header(FORWARDED, "for=198.51.100.7").body(()).unwrap();
+        let head = head(request, "192.0.2.1:5000");
+        assert_eq!(header(&head, "x-forwarded-for"), Some("198.51.100.7, 192.0.2.1"));
+        assert_eq!(header(&head, "x-forwarded-proto"), Some("https"));
+        assert_eq!(header(&head, "forwarded"), Some("for=198.51.100.7, for=192.0.2.1;proto=https;host=\"example.com\""));
+    }
+
+    #[test]
+    fn ipv6_client_is_quoted_in_forwarded() {
+        let request = Request::get("https://example.com/").body(()).unwrap();
+        let head = head(request, "[2001:db8::1]:5000");
+        assert_eq!(header(&head, "host"), Some("example.com"));
+        assert_eq!(header(&head, "x-forwarded-for"), Some("2001:db8::1"));
+        assert_eq!(header(&head, "forwarded"), Some("for=\"[2001:db8::1]\";proto=https;host=\"example.com\""));
+    }
+
+    /// What the client gets when an upstream answers `response` to a
+    /// `method` request: the response head and its body.
+    async fn exchange_with(method: Method, response: &'static [u8]) -> (Response<()>, Bytes) {
+        let (client, mut upstream) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move {
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                upstream.read_exact(&mut byte).await.unwrap();
+                request.push(byte[0]);
+            }
+            upstream.write_all(response).await.unwrap();
+            upstream.shutdown().await.unwrap();
+            // Held open until the client is done, only `shutdown` ends the body.
+            let _ = upstream.read(&mut [0]).await;
+        });
+        let head = format!("{} / HTTP/1.1\r\nHost: localhost\r\n\r\n", method);
+        let (response, mut body) = exchange(Box::new(client), head.as_bytes(), &Bytes::new(), &method, Duration::from_secs(1), None).await.unwrap();
+        let mut contents = BytesMut::new();
+        while let Some(chunk) = body.next_chunk().await.unwrap() {
+            contents.extend_from_slice(&chunk);
+        }
+        (response.body(()).unwrap(), contents.freeze())
+    }
+
+    #[tokio::test]
+    async fn chunked_body_is_unchunked() {
+        let (response, body) = exchange_with(Method::GET, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close, X-Hop\r\nX-Hop: 1\r\nServer: upstream\r\n\r\n5;ext\r\nhello\r\n1\r\n!\r\n0\r\nX-Trailer: 1\r\n\r\n").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body, "hello!");
+        for name in ["transfer-encoding", "content-length", "connection", "x-hop", "server"] {
+            assert!(!response.headers().contains_key(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn content_length_body_ends_at_its_length() {
+        let (response, body) = exchange_with(Method::GET, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello and more").await;
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        assert_eq!(body, "hello");
+    }
+
+    #[tokio::test]
+    async fn close_delimited_body_ends_with_the_connection() {
+        let (response, body) = exchange_with(Method::GET, b"HTTP/1.0 200 OK\r\n\r\nuntil close").await;
+        assert!(!response.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(body, "until close");
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_skipped() {
+        let (response, body) = exchange_with(Method::GET, b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok").await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn head_204_and_304_have_no_body() {
+        let (response, body) = exchange_with(Method::HEAD, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n").await;
+        assert_eq!(response.headers()[CONTENT_LENGTH], "5");
+        assert!(body.is_empty());
+        let (response, body) = exchange_with(Method::GET, b"HTTP/1.1 204 No Content\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(body.is_empty());
+        let (response, body) = exchange_with(Method::GET, b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(body.is_empty());
+    }
+
+    /// The status `forward` answers with when the website's only upstream
+    /// is `upstream`.
+    async fn forward_status(upstream: &str) -> StatusCode {
+        let site = testing::website(&format!("mode = \"Proxy\"\nproxy_pass = [{:?}]\nproxy_timeout = 1\n", upstream));
+        let request = Request::get("/").header(HOST, "localhost").body(()).unwrap();
+        match forward(&site, &request, Bytes::new(), "127.0.0.1:40000".parse().unwrap(), "http").await {
+            Ok((response, _)) => response.body(()).unwrap().status(),
+            Err(status) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_is_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        assert_eq!(forward_status(&addr).await, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn garbage_from_upstream_is_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"not http at all\r\n\r\n").await.unwrap();
+        });
+        assert_eq!(forward_status(&addr).await, StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn silent_upstream_is_gateway_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        assert_eq!(forward_status(&addr).await, StatusCode::GATEWAY_TIMEOUT);
+    }
+}
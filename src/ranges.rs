@@ -0,0 +1,202 @@
+use http::header::{HeaderMap, HeaderValue, CONTENT_TYPE, IF_RANGE, RANGE};
+use http::response::Builder;
+use http::StatusCode;
+use httpdate::fmt_http_date;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use crate::body::{Body, Part};
+use crate::conditional;
+
+/// Narrows the 200 response for a file to the byte ranges asked for in the
+/// `Range` header of the request, answering 206 Partial Content for one or
+/// more satisfiable ranges and 416 Range Not Satisfiable when none are.
+pub fn apply(response: Builder, headers: &HeaderMap, body: Body, etag: &str, last_modified: Option<SystemTime>) -> (Builder, Body) {
+    let mut response = response.header("Accept-Ranges", "bytes");
+    let len = match body.len() {
+        Some(len) => len,
+        None => return (response, body),
+    };
+    let range = match headers.get(RANGE).and_then(|range| range.to_str().ok()) {
+        Some(range) if if_range_matches(headers, etag, last_modified) => range,
+        _ => return (response, body),
+    };
+    let ranges = match parse(range, len) {
+        Some(ranges) => coalesce(ranges),
+        None => return (response, body),
+    };
+    match ranges.len() {
+        0 => {
+            if let Some(headers) = response.headers_mut() {
+                headers.remove(CONTENT_TYPE);
+            }
+            (response.status(StatusCode::RANGE_NOT_SATISFIABLE).header("Content-Range", format!("bytes */{}", len)), Body::empty())
+        },
+        1 => {
+            let (start, end) = ranges[0];
+            (response.status(StatusCode::PARTIAL_CONTENT).header("Content-Range", format!("bytes {}-{}/{}", start, end, len)), body.with_parts(vec![Part::File(start, end - start + 1)]))
+        },
+        _ => {
+            let content_type = response.headers_ref().and_then(|headers| headers.get(CONTENT_TYPE)).cloned().unwrap_or_else(|| HeaderValue::from_static("application/octet-stream"));
+            let boundary = format!("lightron{:x}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos());
+            let mut parts = Vec::new();
+            for (start, end) in ranges {
+                let mut part_head = format!("--{}\r\nContent-Type: ", boundary).into_bytes();
+                part_head.extend_from_slice(content_type.as_bytes());
+                part_head.extend_from_slice(format!("\r\nContent-Range: bytes {}-{}/{}\r\n\r\n", start, end, len).as_bytes());
+                parts.push(Part::Bytes(part_head.into()));
+                parts.push(Part::File(start, end - start + 1));
+                parts.push(Part::Bytes(Bytes::from_static(b"\r\n")));
+            }
+            parts.push(Part::Bytes(format!("--{}--\r\n", boundary).into()));
+            if let Some(headers) = response.headers_mut() {
+                headers.insert(CONTENT_TYPE, HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap());
+            }
+            (response.status(StatusCode::PARTIAL_CONTENT), body.with_parts(parts))
+        },
+    }
+}
+
+/// A `Range` header only applies when the `If-Range` validator, if any, still
+/// matches the file; otherwise the client gets the whole new file.
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    let if_range = match headers.get(IF_RANGE).map(|if_range| if_range.to_str()) {
+        Some(Ok(if_range)) => if_range.trim(),
+        Some(Err(_)) => return false,
+        None => return true,
+    };
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        conditional::strong_eq(if_range, etag)
+    }
+    else {
+        last_modified.map(|last_modified| if_range == fmt_http_date(last_modified)).unwrap_or(false)
+    }
+}
+
+/// Parses a `bytes=` range set into inclusive ranges of a file `len` bytes
+/// long. Returns `None` for a header that is not a valid range set, so it can
+/// be ignored, and an empty list when none of the ranges is satisfiable.
+fn parse(range: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = range.trim().strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    let mut valid = false;
+    for spec in specs.split(',').map(|spec| spec.trim()).filter(|spec| !spec.is_empty()) {
+        let (start, end) = spec.split_once('-')?;
+        valid = true;
+        if start.is_empty() {
+            let suffix: u64 = end.parse().ok()?;
+            if suffix > 0 && len > 0 {
+                ranges.push((len.saturating_sub(suffix), len - 1));
+            }
+        }
+        else {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = if end.is_empty() { u64::MAX } else { end.parse().ok()? };
+            if end < start {
+                return None;
+            }
+            if start < len {
+                ranges.push((start, end.min(len - 1)));
+            }
+        }
+    }
+    if valid {
+        Some(ranges)
+    }
+    else {
+        None
+    }
+}
+
+/// Merges overlapping and adjacent ranges so no byte is sent twice.
+fn coalesce(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::CONTENT_RANGE;
+    use http::Response;
+    use tokio::fs::File;
+    use crate::testing;
+
+    /// The file `contents` served for a request with the `Range` header
+    /// `range`: its status, `Content-Range`, `Content-Type` and body.
+    async fn serve(contents: &[u8], range: &str) -> (StatusCode, Option<String>, Option<String>, Vec<u8>) {
+        let dir = testing::resource(&[("file", contents)]);
+        let file = File::open(format!("{}/file", dir)).await.unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, range.parse().unwrap());
+        let response = Response::builder().status(StatusCode::OK).header(CONTENT_TYPE, "text/plain");
+        let (response, mut body) = apply(response, &headers, Body::from_file(file, contents.len() as u64), "\"etag\"", None);
+        let response = response.body(()).unwrap();
+        let header = |name| response.headers().get(name).map(|value: &HeaderValue| value.to_str().unwrap().to_string());
+        let mut sent = Vec::new();
+        while let Some(chunk) = body.next_chunk().await.unwrap() {
+            sent.extend_from_slice(&chunk);
+        }
+        (response.status(), header(CONTENT_RANGE), header(CONTENT_TYPE), sent)
+    }
+
+    #[test]
+    fn suffix_and_open_ended_ranges() {
+        assert_eq!(parse("bytes=-3", 10), Some(vec![(7, 9)]));
+        assert_eq!(parse("bytes=-30", 10), Some(vec![(0, 9)]));
+        assert_eq!(parse("bytes=4-", 10), Some(vec![(4, 9)]));
+        assert_eq!(parse("bytes=4-100", 10), Some(vec![(4, 9)]));
+        assert_eq!(parse("bytes=10-", 10), Some(vec![]));
+        assert_eq!(parse("bytes=-0", 10), Some(vec![]));
+    }
+
+    #[test]
+    fn invalid_range_sets_are_ignored() {
+        assert_eq!(parse("bytes=5-2", 10), None);
+        assert_eq!(parse("bytes=0-1,5-2", 10), None);
+        assert_eq!(parse("items=0-1", 10), None);
+        assert_eq!(parse("bytes=", 10), None);
+        assert_eq!(parse("bytes=x-1", 10), None);
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_ranges_are_merged() {
+        assert_eq!(coalesce(vec![(5, 9), (0, 2), (1, 3)]), vec![(0, 3), (5, 9)]);
+        assert_eq!(coalesce(vec![(0, 2), (3, 4)]), vec![(0, 4)]);
+        assert_eq!(coalesce(vec![(0, 9), (2, 3)]), vec![(0, 9)]);
+        assert_eq!(coalesce(vec![(0, 1), (3, 4)]), vec![(0, 1), (3, 4)]);
+    }
+
+    #[tokio::test]
+    async fn single_range_is_partial_content() {
+        assert_eq!(serve(b"0123456789", "bytes=-3").await, (StatusCode::PARTIAL_CONTENT, Some("bytes 7-9/10".to_string()), Some("text/plain".to_string()), b"789".to_vec()));
+        assert_eq!(serve(b"0123456789", "bytes=0-2,3-4").await.1, Some("bytes 0-4/10".to_string()));
+    }
+
+    #[tokio::test]
+    async fn end_before_start_sends_the_whole_file() {
+        assert_eq!(serve(b"0123456789", "bytes=5-2").await, (StatusCode::OK, None, Some("text/plain".to_string()), b"0123456789".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn unsatisfiable_ranges() {
+        assert_eq!(serve(b"", "bytes=0-").await, (StatusCode::RANGE_NOT_SATISFIABLE, Some("bytes */0".to_string()), None, Vec::new()));
+        assert_eq!(serve(b"", "bytes=-5").await.0, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(serve(b"0123456789", "bytes=10-20").await.1, Some("bytes */10".to_string()));
+    }
+
+    #[tokio::test]
+    async fn several_ranges_are_multipart() {
+        let (status, content_range, content_type, sent) = serve(b"0123456789", "bytes=8-,0-1").await;
+        assert_eq!((status, content_range), (StatusCode::PARTIAL_CONTENT, None));
+        let boundary = content_type.unwrap().strip_prefix("multipart/byteranges; boundary=").unwrap().to_string();
+        let expected = format!("--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n--{0}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{0}--\r\n", boundary);
+        assert_eq!(String::from_utf8(sent).unwrap(), expected);
+    }
+}
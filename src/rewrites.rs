@@ -0,0 +1,297 @@
+use http::{StatusCode, Uri};
+use log::{debug, warn};
+use regex::Regex;
+use crate::{paths, web_docs, Website};
+
+/// A rewrite rule of a website, its patterns compiled.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Regex,
+    replacement: String,
+    redirect: Option<StatusCode>,
+    host: Option<Regex>,
+    scheme: Option<String>,
+    query: Option<Regex>,
+}
+
+/// What the rewrite rules make of a request.
+pub enum Rewritten {
+    Unchanged,
+    /// Served as if this had been asked for.
+    Internal(Uri),
+    /// Answered with a redirect to this location.
+    Redirect(StatusCode, String),
+}
+
+/// Compiles the website's rewrite rules once, before it is served.
+pub fn prepare(mut website: Website) -> Website {
+    let rules = website.rewrites.iter().map(|rewrite| Rule {
+        pattern: compile(&rewrite.pattern),
+        replacement: rewrite.replacement.clone(),
+        redirect: rewrite.redirect.map(|status| match status {
+            301 | 302 | 307 | 308 => StatusCode::from_u16(status).unwrap(),
+            _ => panic!("rewrite of {} redirects with {}, not 301, 302, 307 or 308", website.name, status),
+        }),
+        host: rewrite.host.as_deref().map(compile),
+        scheme: rewrite.scheme.clone(),
+        query: rewrite.query.as_deref().map(compile),
+    }).collect();
+    website.rules = rules;
+    website
+}
+
+fn compile(pattern: &str) -> Regex {
+    Regex::new(pattern).unwrap_or_else(|err| panic!("invalid rewrite pattern {:?} : {}", pattern, err))
+}
+
+/// Runs a request through the website's rules, in order, before its path is
+/// looked up. `scheme` is the one the client used, `http` or `https`.
+pub fn apply(site: &Website, uri: &Uri, host: Option<&str>, scheme: &str) -> Rewritten {
+    if site.rules.is_empty() {
+        return Rewritten::Unchanged;
+    }
+    let path = match paths::normalize(uri.path()) {
+        Ok(path) => path,
+        // Answered as a bad path once looked up.
+        Err(_) => return Rewritten::Unchanged,
+    };
+    let (mut rewritten, _) = run(&site.rules, path, uri.query(), host.map(web_docs::host_name).unwrap_or(""), scheme);
+    if let (Rewritten::Internal(rewritten), Some(scheme), Some(authority)) = (&mut rewritten, uri.scheme(), uri.authority()) {
+        // HTTP/2 requests carry their scheme and authority in the URI.
+        if let Ok(absolute) = Uri::builder().scheme(scheme.clone()).authority(authority.clone()).path_and_query(rewritten.to_string()).build() {
+            *rewritten = absolute;
+        }
+    }
+    match &rewritten {
+        Rewritten::Unchanged => {},
+        Rewritten::Internal(rewritten) => debug!("{} rewrite {} -> {}", site.port_no, uri, rewritten),
+        Rewritten::Redirect(status, location) => debug!("{} rewrite {} -> {} {}", site.port_no, uri, status.as_str(), location),
+    }
+    rewritten
+}
+
+/// Matches the rules against a normalized path, each rule seeing the path
+/// the ones before it rewrote, until one redirects. Also answers which rules
+/// matched.
+fn run(rules: &[Rule], mut path: String, query: Option<&str>, host: &str, scheme: &str) -> (Rewritten, Vec<usize>) {
+    let mut query = query.map(|query| query.to_string());
+    let mut matched = Vec::new();
+    for (i, rule) in rules.iter().enumerate() {
+        let applies = rule.scheme.as_ref().map(|rule_scheme| rule_scheme.eq_ignore_ascii_case(scheme)).unwrap_or(true)
+            && rule.host.as_ref().map(|rule_host| rule_host.is_match(host)).unwrap_or(true)
+            && rule.query.as_ref().map(|rule_query| rule_query.is_match(query.as_deref().unwrap_or(""))).unwrap_or(true);
+        if !applies {
+            continue;
+        }
+        let captures = match rule.pattern.captures(&path) {
+            Some(captures) => captures,
+            None => continue,
+        };
+        let mut target = String::new();
+        captures.expand(&rule.replacement, &mut target);
+        matched.push(i);
+        // A query in the replacement takes the place of the request's, an
+        // empty one drops it.
+        let (target_path, target_query) = match target.split_once('?') {
+            Some((target_path, "")) => (target_path.to_string(), None),
+            Some((target_path, target_query)) => (target_path.to_string(), Some(target_query.to_string())),
+            None => (target, query.clone()),
+        };
+        if let Some(status) = rule.redirect {
+            return (Rewritten::Redirect(status, location(&target_path, target_query.as_deref())), matched);
+        }
+        path = target_path;
+        query = target_query;
+    }
+    if matched.is_empty() {
+        return (Rewritten::Unchanged, matched);
+    }
+    let target = location(&path, query.as_deref());
+    match target.parse() {
+        Ok(uri) => (Rewritten::Internal(uri), matched),
+        Err(_) => {
+            warn!("rewrite to {} is not a valid path", target);
+            (Rewritten::Unchanged, matched)
+        },
+    }
+}
+
+/// Escapes a rewritten path, or the path of a rewritten URL, so it can go
+/// into a URI again.
+fn location(target: &str, query: Option<&str>) -> String {
+    let path_start = match target.find("://") {
+        Some(scheme_end) => target[scheme_end + 3..].find('/').map(|slash| scheme_end + 3 + slash).unwrap_or(target.len()),
+        None => 0,
+    };
+    let location = format!("{}{}", &target[..path_start], paths::encode(&target[path_start..]));
+    match query {
+        Some(query) => format!("{}?{}", location, query),
+        None => location,
+    }
+}
+
+/// The website the server would answer `host` with on `port`: the only one
+/// on the port, or the one named `host` when the port has several.
+fn site_serving<'a>(websites: &'a [Website], port: u16, host: &str) -> Option<&'a Website> {
+    let on_port: Vec<&Website> = websites.iter().filter(|website| website.port_no == port).collect();
+    match on_port.as_slice() {
+        [site] => Some(site),
+        sites => sites.iter().copied().find(|website| website.name == web_docs::host_name(host)),
+    }
+}
+
+/// Prints what the rules of the website serving `url` make of it, for
+/// `--test-rewrite`.
+pub fn test(websites: &[Website], url: &str) {
+    let uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(err) => {
+            println!("{} : {}", url, err);
+            return;
+        },
+    };
+    let scheme = uri.scheme_str().unwrap_or("http");
+    let host = uri.host().unwrap_or("");
+    let port = uri.port_u16().unwrap_or(if scheme == "https" { 443 } else { 80 });
+    let site = match site_serving(websites, port, host) {
+        Some(site) => site,
+        None => {
+            println!("no website serves {}", url);
+            return;
+        },
+    };
+    println!("website {} on port {}", site.name, site.port_no);
+    let path = match paths::normalize(uri.path()) {
+        Ok(path) => path,
+        Err(status) => {
+            println!("{} is answered with {}", uri.path(), status);
+            return;
+        },
+    };
+    let (rewritten, matched) = run(&site.rules, path, uri.query(), web_docs::host_name(host), scheme);
+    for i in matched {
+        println!("rule {} : {} -> {}", i + 1, site.rewrites[i].pattern, site.rewrites[i].replacement);
+    }
+    match rewritten {
+        Rewritten::Unchanged => println!("unchanged"),
+        Rewritten::Internal(uri) => println!("rewritten to {}", uri),
+        Rewritten::Redirect(status, location) => println!("{} redirect to {}", status, location),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// The compiled rules of `rewrites`, the `[[websites.rewrites]]` tables
+    /// of a website.
+    fn rules(rewrites: &str) -> Vec<Rule> {
+        testing::website(rewrites).rules
+    }
+
+    /// What `rules` make of `target` on a request to `host` over `scheme`,
+    /// with the rules that matched.
+    fn outcome(rules: &[Rule], target: &str, host: &str, scheme: &str) -> (String, Vec<usize>) {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+        let (rewritten, matched) = run(rules, path.to_string(), query, host, scheme);
+        let rewritten = match rewritten {
+            Rewritten::Unchanged => "unchanged".to_string(),
+            Rewritten::Internal(uri) => uri.to_string(),
+            Rewritten::Redirect(status, location) => format!("{} {}", status.as_str(), location),
+        };
+        (rewritten, matched)
+    }
+
+    fn rewrite(rules: &[Rule], target: &str) -> String {
+        outcome(rules, target, "example.com", "https").0
+    }
+
+    #[test]
+    fn captures_are_expanded() {
+        let rules = rules("[[websites.rewrites]]\npattern = '^/user/(\\d+)/(?P<tab>\\w+)$'\nreplacement = '/profile?id=$1&tab=${tab}'\n");
+        assert_eq!(rewrite(&rules, "/user/42/info"), "/profile?id=42&tab=info");
+        assert_eq!(rewrite(&rules, "/user/x/info"), "unchanged");
+    }
+
+    #[test]
+    fn internal_rewrites_chain() {
+        let rules = rules("[[websites.rewrites]]\npattern = '^/old/(.*)'\nreplacement = '/mid/$1'\n[[websites.rewrites]]\npattern = '^/unrelated'\nreplacement = '/x'\n[[websites.rewrites]]\npattern = '^/mid/(.*)'\nreplacement = '/new/$1'\n");
+        assert_eq!(outcome(&rules, "/old/a b", "example.com", "https"), ("/new/a%20b".to_string(), vec![0, 2]));
+    }
+
+    #[test]
+    fn query_is_kept_replaced_or_dropped() {
+        let rules = rules("[[websites.rewrites]]\npattern = '^/keep$'\nreplacement = '/kept'\n[[websites.rewrites]]\npattern = '^/replace$'\nreplacement = '/replaced?b=2'\n[[websites.rewrites]]\npattern = '^/drop$'\nreplacement = '/dropped?'\n");
+        assert_eq!(rewrite(&rules, "/keep?a=1"), "/kept?a=1");
+        assert_eq!(rewrite(&rules, "/replace?a=1"), "/replaced?b=2");
+        assert_eq!(rewrite(&rules, "/drop?a=1"), "/dropped");
+    }
+
+    #[test]
+    fn redirect_ends_the_rules_with_its_status() {
+        for status in [301, 302, 307, 308] {
+            let rules = rules(&format!("[[websites.rewrites]]\npattern = '^/a/(.*)'\nreplacement = 'https://example.org/b/$1'\nredirect = {}\n[[websites.rewrites]]\npattern = '.*'\nreplacement = '/never'\n", status));
+            assert_eq!(outcome(&rules, "/a/c d?q=1", "example.com", "https"), (format!("{} https://example.org/b/c%20d?q=1", status), vec![0]));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not 301, 302, 307 or 308")]
+    fn other_redirect_statuses_are_refused() {
+        rules("[[websites.rewrites]]\npattern = '.*'\nreplacement = '/'\nredirect = 303\n");
+    }
+
+    #[test]
+    fn host_condition() {
+        let rules = rules("[[websites.rewrites]]\npattern = '^/(.*)'\nreplacement = 'https://example.com/$1'\nredirect = 301\nhost = '^www\\.'\n");
+        assert_eq!(outcome(&rules, "/a", "www.example.com", "https").0, "301 https://example.com/a");
+        assert_eq!(outcome(&rules, "/a", "example.com", "https").0, "unchanged");
+    }
+
+    #[test]
+    fn scheme_condition() {
+        let rules = rules("[[websites.rewrites]]\npattern = '^/(.*)'\nreplacement = 'https://example.com/$1'\nredirect = 308\nscheme = 'http'\n");
+        assert_eq!(outcome(&rules, "/a", "example.com", "HTTP").0, "308 https://example.com/a");
+        assert_eq!(outcome(&rules, "/a", "example.com", "https").0, "unchanged");
+    }
+
+    #[test]
+    fn query_condition() {
+        let rules = rules("[[websites.rewrites]]\npattern = '^/$'\nreplacement = '/fr/'\nquery = '(^|&)lang=fr(&|$)'\n");
+        assert_eq!(rewrite(&rules, "/?lang=fr"), "/fr/?lang=fr");
+        assert_eq!(rewrite(&rules, "/?lang=frx"), "unchanged");
+        assert_eq!(rewrite(&rules, "/"), "unchanged");
+    }
+
+    fn websites() -> Vec<Website> {
+        vec![
+            testing::website("name = \"alone\"\nport_no = 8080"),
+            testing::website("name = \"a.example\"\nport_no = 8081"),
+            testing::website("name = \"b.example\"\nport_no = 8081"),
+        ]
+    }
+
+    #[test]
+    fn only_site_on_port_serves_any_host() {
+        let websites = websites();
+        assert_eq!(site_serving(&websites, 8080, "other.example").unwrap().name, "alone");
+    }
+
+    #[test]
+    fn shared_port_serves_by_name() {
+        let websites = websites();
+        assert_eq!(site_serving(&websites, 8081, "b.example").unwrap().name, "b.example");
+        assert_eq!(site_serving(&websites, 8081, "b.example:8081").unwrap().name, "b.example");
+    }
+
+    #[test]
+    fn unknown_host_or_port_is_not_served() {
+        let websites = websites();
+        assert!(site_serving(&websites, 8081, "alone").is_none());
+        assert!(site_serving(&websites, 8082, "a.example").is_none());
+    }
+}
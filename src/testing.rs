@@ -0,0 +1,51 @@
+//! Websites for the unit tests, written as lightron.conf would have them.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use toml::from_str;
+use crate::{locations, rewrites, Config, Website};
+
+/// Settings every website has to have, used for those `settings` leaves out.
+const REQUIRED: [(&str, &str); 9] = [
+    ("name", "\"localhost\""),
+    ("class", "\"HTTP\""),
+    ("access", "\"Local\""),
+    ("resource", "\"\""),
+    ("certificate", "\"\""),
+    ("private_key", "\"\""),
+    ("port_no", "8080"),
+    ("push_protocol_files", "[]"),
+    ("log_level", "\"Off\""),
+];
+
+/// A website with `settings`, the lines of its `[[websites]]` table, prepared
+/// like `main` prepares it. Tables of the website, such as `[[websites.locations]]`,
+/// follow the keys in `settings`.
+pub fn website(settings: &str) -> Website {
+    let mut conf = "[[websites]]\n".to_string();
+    for (key, value) in REQUIRED {
+        if !settings.lines().any(|line| line.split('=').next().map(str::trim) == Some(key)) {
+            conf += &format!("{} = {}\n", key, value);
+        }
+    }
+    conf += settings;
+    let config: Config = from_str(&conf).unwrap();
+    locations::prepare(rewrites::prepare(config.websites[0].clone()))
+}
+
+/// The website, for as long as the tests run.
+pub fn leak(website: Website) -> &'static Website {
+    Box::leak(Box::new(website))
+}
+
+/// A fresh directory holding `files`, by path relative to it, to serve as a
+/// website's resource.
+pub fn resource(files: &[(&str, &[u8])]) -> String {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("lightron-test-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst)));
+    for (path, contents) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.to_str().unwrap().to_string()
+}
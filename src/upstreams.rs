@@ -0,0 +1,336 @@
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+use crate::proxy::{self, Upstream};
+use crate::{UpstreamGroup, Website};
+
+/// The configured upstream groups, by name.
+static POOLS: OnceLock<HashMap<String, &'static Pool>> = OnceLock::new();
+
+/// A group's servers and their state, shared by every listener.
+pub struct Pool {
+    group: UpstreamGroup,
+    servers: Vec<Server>,
+    /// Where the next round-robin pick starts.
+    next: AtomicUsize,
+}
+
+/// One server of a group.
+pub struct Server {
+    group: &'static str,
+    upstream: Upstream,
+    /// Requests in flight, for least-connections.
+    active: AtomicUsize,
+    /// Failures since the last success.
+    fails: AtomicU32,
+    /// Set while the server is considered down.
+    down_since: Mutex<Option<Instant>>,
+    max_fails: u32,
+    fail_timeout: Duration,
+    health_checked: bool,
+}
+
+/// An upstream to try for a request, with the group server it belongs to
+/// when it was picked from an `upstreams` group.
+pub struct Candidate {
+    pub upstream: Upstream,
+    pub server: Option<&'static Server>,
+}
+
+/// Counts a request in flight on a server until dropped.
+pub struct Lease(&'static Server);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Sets up the configured groups, once before the listeners start.
+pub fn init(groups: Vec<UpstreamGroup>) {
+    let mut pools = HashMap::new();
+    for group in groups {
+        let pool = pool(group);
+        pools.insert(pool.group.name.clone(), pool);
+    }
+    POOLS.set(pools).ok();
+}
+
+/// A group with all of its servers up.
+fn pool(group: UpstreamGroup) -> &'static Pool {
+    let name: &'static str = Box::leak(group.name.clone().into_boxed_str());
+    if !matches!(group.strategy.as_str(), "RoundRobin" | "LeastConnections" | "IpHash") {
+        warn!("upstream group {} : unknown strategy {}, using RoundRobin", name, group.strategy);
+    }
+    let servers = group.servers.iter().map(|address| Server {
+        group: name,
+        upstream: Upstream::parse(address),
+        active: AtomicUsize::new(0),
+        fails: AtomicU32::new(0),
+        down_since: Mutex::new(None),
+        max_fails: group.max_fails.max(1),
+        fail_timeout: Duration::from_secs(group.fail_timeout),
+        health_checked: group.health_check.is_some(),
+    }).collect();
+    Box::leak(Box::new(Pool { group, servers, next: AtomicUsize::new(0) }))
+}
+
+fn pools() -> impl Iterator<Item = &'static Pool> {
+    POOLS.get().into_iter().flat_map(|pools| pools.values().copied())
+}
+
+pub fn has_health_checks() -> bool {
+    pools().any(|pool| pool.group.health_check.is_some())
+}
+
+/// The upstreams to try for a request from `client`, in order. An entry of
+/// `proxy_pass` naming a group stands for its live servers, in the order its
+/// strategy prefers them, any other entry is an address.
+pub fn candidates(site: &Website, client: IpAddr) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    for entry in &site.proxy_pass {
+        match POOLS.get().and_then(|pools| pools.get(entry)) {
+            Some(pool) => candidates.extend(pool.pick(client).into_iter().map(|server| Candidate { upstream: server.upstream.clone(), server: Some(server) })),
+            None => candidates.push(Candidate { upstream: Upstream::parse(entry), server: None }),
+        }
+    }
+    candidates
+}
+
+impl Pool {
+    /// The servers that are up, the one the strategy picks first and the
+    /// others after it to fail over to.
+    fn pick(&'static self, client: IpAddr) -> Vec<&'static Server> {
+        let live: Vec<&'static Server> = self.servers.iter().filter(|server| server.is_available()).collect();
+        if live.is_empty() {
+            return live;
+        }
+        let start = match self.group.strategy.as_str() {
+            // Hashed over all the servers, so clients only move when their
+            // own server goes down.
+            "IpHash" => {
+                let preferred = &self.servers[(hash(client) % self.servers.len() as u64) as usize];
+                live.iter().position(|server| std::ptr::eq(*server, preferred)).unwrap_or(0)
+            },
+            _ => self.next.fetch_add(1, Ordering::Relaxed) % live.len(),
+        };
+        let mut servers: Vec<&'static Server> = (0..live.len()).map(|i| live[(start + i) % live.len()]).collect();
+        if self.group.strategy == "LeastConnections" {
+            // Stable, so ties keep rotating.
+            servers.sort_by_key(|server| server.active.load(Ordering::Relaxed));
+        }
+        servers
+    }
+}
+
+/// FNV-1a, so a client keeps its server across restarts.
+fn hash(client: IpAddr) -> u64 {
+    let octets = match client {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.octets().to_vec(),
+            None => ip.octets().to_vec(),
+        },
+    };
+    octets.iter().fold(0xcbf29ce484222325, |hash, octet| (hash ^ *octet as u64).wrapping_mul(0x100000001b3))
+}
+
+impl Server {
+    /// Whether requests may be sent to the server. Without health checks a
+    /// server that is down gets another chance after `fail_timeout`, with
+    /// them it waits for a check to pass.
+    fn is_available(&self) -> bool {
+        match *self.down_since.lock().unwrap() {
+            None => true,
+            Some(since) => !self.health_checked && since.elapsed() >= self.fail_timeout,
+        }
+    }
+
+    pub fn lease(&'static self) -> Lease {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Lease(self)
+    }
+
+    /// Records a request the server failed, marking it down after
+    /// `max_fails` of them in a row.
+    pub fn failed(&self) {
+        let fails = self.fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails >= self.max_fails {
+            let mut down_since = self.down_since.lock().unwrap();
+            if down_since.is_none() {
+                warn!("upstream {} {} is down after {} failures", self.group, self.upstream, fails);
+            }
+            *down_since = Some(Instant::now());
+        }
+    }
+
+    /// Records a request the server answered, bringing it back up.
+    pub fn succeeded(&self) {
+        self.fails.store(0, Ordering::Relaxed);
+        let mut down_since = self.down_since.lock().unwrap();
+        if down_since.take().is_some() {
+            info!("upstream {} {} is up", self.group, self.upstream);
+        }
+    }
+
+    /// Records the outcome of a health check, which takes effect at once.
+    fn checked(&self, healthy: bool) {
+        if healthy {
+            self.succeeded();
+            return;
+        }
+        self.fails.store(self.max_fails, Ordering::Relaxed);
+        let mut down_since = self.down_since.lock().unwrap();
+        if down_since.is_none() {
+            warn!("upstream {} {} is down, health check failed", self.group, self.upstream);
+            *down_since = Some(Instant::now());
+        }
+    }
+}
+
+/// Checks the servers of every group with a `health_check` path every
+/// `health_check_interval` seconds, a server is healthy when it answers the
+/// path with a 2xx or 3xx.
+#[tokio::main]
+pub async fn health_checks() {
+    let mut checks = Vec::new();
+    for pool in pools() {
+        let path = match &pool.group.health_check {
+            Some(path) => path.as_str(),
+            None => continue,
+        };
+        let interval = Duration::from_secs(pool.group.health_check_interval.max(1));
+        checks.push(tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                for server in &pool.servers {
+                    let healthy = match timeout(interval, proxy::probe(&server.upstream, path)).await {
+                        Ok(Ok(status)) => {
+                            debug!("upstream {} {} health check {}", server.group, server.upstream, status.as_str());
+                            status.is_success() || status.is_redirection()
+                        },
+                        Ok(Err(err)) => {
+                            debug!("upstream {} {} health check : {}", server.group, server.upstream, err);
+                            false
+                        },
+                        Err(_) => {
+                            debug!("upstream {} {} health check timed out", server.group, server.upstream);
+                            false
+                        },
+                    };
+                    server.checked(healthy);
+                }
+            }
+        }));
+    }
+    for check in checks {
+        check.await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(strategy: &str, max_fails: u32, fail_timeout: u64, health_check: Option<&str>) -> &'static Pool {
+        pool(UpstreamGroup {
+            name: "app".to_string(),
+            servers: vec!["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string(), "10.0.0.3:80".to_string()],
+            strategy: strategy.to_string(),
+            max_fails,
+            fail_timeout,
+            health_check: health_check.map(str::to_string),
+            health_check_interval: 1,
+        })
+    }
+
+    fn order(pool: &'static Pool, client: &str) -> Vec<String> {
+        pool.pick(client.parse().unwrap()).iter().map(|server| server.upstream.to_string()).collect()
+    }
+
+    #[test]
+    fn round_robin_starts_at_the_next_server_each_time() {
+        let pool = group("RoundRobin", 1, 10, None);
+        assert_eq!(order(pool, "192.0.2.1"), ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80"]);
+        assert_eq!(order(pool, "192.0.2.1"), ["10.0.0.2:80", "10.0.0.3:80", "10.0.0.1:80"]);
+        assert_eq!(order(pool, "192.0.2.1"), ["10.0.0.3:80", "10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(order(pool, "192.0.2.1")[0], "10.0.0.1:80");
+    }
+
+    #[test]
+    fn least_connections_prefers_idle_servers() {
+        let pool = group("LeastConnections", 1, 10, None);
+        let _leases = [pool.servers[0].lease(), pool.servers[0].lease(), pool.servers[1].lease()];
+        assert_eq!(order(pool, "192.0.2.1"), ["10.0.0.3:80", "10.0.0.2:80", "10.0.0.1:80"]);
+    }
+
+    #[test]
+    fn leases_count_until_dropped() {
+        let pool = group("LeastConnections", 1, 10, None);
+        drop(pool.servers[0].lease());
+        assert_eq!(pool.servers[0].active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn ip_hash_keeps_a_client_on_its_server_while_it_is_up() {
+        let pool = group("IpHash", 1, 10, Some("/health"));
+        let preferred = order(pool, "192.0.2.1")[0].clone();
+        for _ in 0..5 {
+            assert_eq!(order(pool, "192.0.2.1")[0], preferred);
+        }
+        // The same client over IPv6 as IPv4-mapped.
+        assert_eq!(order(pool, "::ffff:192.0.2.1")[0], preferred);
+        let server = pool.servers.iter().find(|server| server.upstream.to_string() == preferred).unwrap();
+        server.checked(false);
+        assert!(!order(pool, "192.0.2.1").contains(&preferred));
+        server.checked(true);
+        assert_eq!(order(pool, "192.0.2.1")[0], preferred);
+    }
+
+    #[test]
+    fn server_is_down_after_max_fails_in_a_row() {
+        let pool = group("RoundRobin", 2, 10, None);
+        let server = &pool.servers[0];
+        server.failed();
+        server.succeeded();
+        server.failed();
+        assert!(server.is_available());
+        server.failed();
+        assert!(!server.is_available());
+        assert_eq!(order(pool, "192.0.2.1").len(), 2);
+        server.succeeded();
+        assert!(server.is_available());
+    }
+
+    #[test]
+    fn server_gets_another_chance_after_fail_timeout() {
+        let pool = group("RoundRobin", 1, 0, None);
+        pool.servers[0].failed();
+        assert!(pool.servers[0].is_available());
+    }
+
+    #[test]
+    fn health_checked_server_waits_for_a_check() {
+        let pool = group("RoundRobin", 3, 0, Some("/health"));
+        let server = &pool.servers[0];
+        server.checked(false);
+        assert!(!server.is_available());
+        server.checked(true);
+        assert!(server.is_available());
+        assert_eq!(server.fails.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn no_server_is_picked_when_all_are_down() {
+        let pool = group("RoundRobin", 1, 10, None);
+        for server in &pool.servers {
+            server.failed();
+        }
+        assert!(order(pool, "192.0.2.1").is_empty());
+    }
+}
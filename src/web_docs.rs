@@ -0,0 +1,388 @@
+use http::header::{HeaderMap, HeaderValue, LAST_MODIFIED};
+use http::response::Builder;
+use http::{Response, StatusCode, Uri};
+use httpdate::fmt_http_date;
+use linkcheck::validation::{resolve_link, Options, Reason};
+use log::{debug, warn};
+use mime_guess::Mime;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::body::Body;
+use crate::compression::{self, Encoding};
+use crate::{autoindex, conditional, error_pages, locations, paths, ranges, DomainMap, Website};
+
+/// Where the path of a request leads within a website.
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    Found(PathBuf),
+    Directory(PathBuf),
+    NotFound,
+    Forbidden,
+}
+
+/// Resolves `file_path` of a request against the website's `parent_path`.
+/// Paths that lead out of it, whether they exist or not, are `Forbidden`.
+pub async fn resolve(parent_path: &str, file_path: &str) -> Resolved {
+    if escapes(file_path) {
+        return Resolved::Forbidden;
+    }
+    let (parent_path, file_path) = if cfg!(target_os = "windows") {
+        (parent_path.replace('/', "\\"), file_path.replace('/', "\\"))
+    }
+    else {
+        (parent_path.to_string(), file_path.to_string())
+    };
+    // linkcheck canonicalizes the path on disk, keep that off the runtime's worker threads.
+    tokio::task::spawn_blocking(move || {
+        let linkcheck_options = match Options::new().with_root_directory(parent_path.clone()) {
+            Ok(linkcheck_options) => linkcheck_options.set_links_may_traverse_the_root_directory(false).set_default_file(""),
+            Err(_) => return Resolved::NotFound,
+        };
+        match resolve_link(Path::new(&parent_path), Path::new(&file_path), &linkcheck_options) {
+            Ok(path) if path.is_dir() => Resolved::Directory(path),
+            Ok(path) => Resolved::Found(path),
+            Err(Reason::TraversesParentDirectories) => Resolved::Forbidden,
+            Err(Reason::Io(err)) if err.kind() == io::ErrorKind::PermissionDenied => Resolved::Forbidden,
+            Err(_) => Resolved::NotFound,
+        }
+    }).await.unwrap()
+}
+
+/// Catches paths that climb above the root before anything on disk is
+/// looked at, so a traversal attempt can't tell which files exist outside
+/// of it. Backslashes count as separators whatever the platform, and null
+/// bytes are never part of a file name.
+fn escapes(file_path: &str) -> bool {
+    if file_path.contains('\0') {
+        return true;
+    }
+    let mut depth: usize = 0;
+    for segment in file_path.split(['/', '\\']) {
+        match segment {
+            "" | "." => {},
+            ".." => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return true,
+            },
+            _ => depth += 1,
+        }
+    }
+    false
+}
+
+/// The website a request is for: the one named by its host when websites
+/// share the port. `None` when no website of the port has that name.
+pub fn site_for<'a>(website: &'a Website, domain_map: Option<&'a DomainMap>, host: Option<&str>) -> Option<&'a Website> {
+    let domain_map = match domain_map {
+        Some(domain_map) => domain_map,
+        None => return Some(website),
+    };
+    domain_map.get(host_name(host?)).copied()
+}
+
+/// The name in a `Host` header, which may carry a port, `[...]` wraps IPv6
+/// addresses.
+pub fn host_name(host: &str) -> &str {
+    let name = match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    };
+    name.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Opens a file of the website. Failures are mapped to the status the
+/// request is answered with.
+pub async fn open(file_path: &Path) -> Result<(Body, Option<SystemTime>), StatusCode> {
+    let file = tokio::fs::File::open(file_path).await.map_err(|err| error_status(&err))?;
+    let metadata = file.metadata().await.map_err(|err| error_status(&err))?;
+    if !metadata.is_file() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok((Body::from_file(file, metadata.len()), metadata.modified().ok()))
+}
+
+fn error_status(err: &io::Error) -> StatusCode {
+    match err.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Rewrites `uri_path` to the first entry of the website's `try_files` that
+/// exists, `$uri` standing for the requested path. Entries ending in a slash
+/// only match directories, the others only files. Without a match, or without
+/// `try_files`, the path stays as it is.
+pub async fn try_files(site: &Website, uri_path: &str) -> String {
+    // A traversal attempt is answered as such, not with a fallback.
+    if escapes(uri_path) {
+        return uri_path.to_string();
+    }
+    for try_file in &site.try_files {
+        let candidate = try_file.replace("$uri", uri_path);
+        let found = match resolve(&site.resource, &candidate).await {
+            Resolved::Directory(_) => candidate.ends_with('/'),
+            Resolved::Found(_) => !candidate.ends_with('/'),
+            Resolved::NotFound | Resolved::Forbidden => false,
+        };
+        if found {
+            return candidate;
+        }
+    }
+    uri_path.to_string()
+}
+
+/// What a request is answered with once its path was resolved.
+pub enum Target {
+    File(PathBuf, Mime),
+    Listing(Mime, Body),
+    Redirect(String),
+}
+
+/// Finds what answers a request for `uri` on the website: the path is
+/// normalized, run through `try_files` and resolved under the website's
+/// resource, less the location prefix an alias stands for. Errors are the
+/// status to answer with.
+pub async fn locate(site: &Website, uri: &Uri, headers: &HeaderMap) -> Result<Target, StatusCode> {
+    let uri_path = paths::normalize(uri.path())?;
+    let path = try_files(site, &locations::file_path(site, &uri_path)).await;
+    let content_type = mime_guess::from_path(&path).first_or(mime_guess::mime::TEXT_HTML);
+    target(resolve(&site.resource, &path).await, &uri_path, uri.query(), content_type, site, headers).await
+}
+
+/// Answers a request for `uri` on a static website with what `locate` finds,
+/// or the error page of what went wrong.
+pub async fn serve(site: &Website, uri: &Uri, headers: &HeaderMap, peer_addr: SocketAddr) -> (Builder, Body) {
+    let port_no = site.port_no;
+    match locate(site, uri, headers).await {
+        Err(StatusCode::FORBIDDEN) => {
+            warn!("{} SECURITY 403 {:?} from {}",port_no,uri.path(),peer_addr);
+            error_pages::response(site, StatusCode::FORBIDDEN).await
+        },
+        Err(status) => {
+            warn!("{} {} {}",port_no,status.as_str(),uri.path());
+            error_pages::response(site, status).await
+        },
+        Ok(Target::Redirect(location)) => {
+            debug!("{} 301 {}",port_no,location);
+            (Response::builder().status(StatusCode::MOVED_PERMANENTLY).header("Date", fmt_http_date(SystemTime::now())).header("Location", location).header("Content-Length", 0), Body::empty())
+        },
+        Ok(Target::Listing(content_type, contents)) => {
+            (Response::builder().status(StatusCode::OK).header("Date", fmt_http_date(SystemTime::now())).header("Content-Type", format!("{}",content_type)).header("Vary", "Accept").header("Content-Length", contents.len().unwrap_or(0)), contents)
+        },
+        Ok(Target::File(file_path, content_type)) => {
+            let precompressed = precompressed(&file_path, headers).await;
+            match open(&file_path).await {
+                Ok((contents,last_modified)) => {
+                    let mut response = Response::builder().status(StatusCode::OK).header("Date", fmt_http_date(SystemTime::now())).header("Content-Type", format!("{}",content_type));
+                    if let Some(last_modified) = last_modified {
+                        response = response.header("Last-Modified", fmt_http_date(last_modified));
+                    }
+                    file_response(response, headers, site, &content_type, contents, last_modified, precompressed)
+                },
+                Err(status) => {
+                    warn!("{} {} {}",port_no,status.as_str(),uri.path());
+                    error_pages::response(site, status).await
+                },
+            }
+        },
+    }
+}
+
+/// Resolves a directory to the first of the website's index files in it, or
+/// to a listing of it when the website has autoindex on. Directories asked for
+/// without the trailing slash are redirected to it first, so relative links in
+/// their pages resolve against the directory. Files are read as they are,
+/// anything else is answered with the error status returned.
+async fn target(resolved: Resolved, uri_path: &str, query: Option<&str>, content_type: Mime, site: &Website, headers: &HeaderMap) -> Result<Target, StatusCode> {
+    let file_path = match resolved {
+        Resolved::Found(file_path) => return Ok(Target::File(file_path, content_type)),
+        Resolved::Directory(file_path) => file_path,
+        Resolved::NotFound => return Err(StatusCode::NOT_FOUND),
+        Resolved::Forbidden => return Err(StatusCode::FORBIDDEN),
+    };
+    if !uri_path.ends_with('/') {
+        return Ok(Target::Redirect(match query {
+            Some(query) => format!("{}/?{}", paths::encode(uri_path), query),
+            None => format!("{}/", paths::encode(uri_path)),
+        }));
+    }
+    for index_file in &site.index_files {
+        // The directory is canonical already, an index file that is a link
+        // must not lead out of it.
+        match tokio::fs::canonicalize(file_path.join(index_file)).await {
+            Ok(index_path) if index_path.starts_with(&file_path) && tokio::fs::metadata(&index_path).await.map(|metadata| metadata.is_file()).unwrap_or(false) => {
+                let content_type = mime_guess::from_path(&index_path).first_or(mime_guess::mime::TEXT_HTML);
+                return Ok(Target::File(index_path, content_type));
+            },
+            _ => continue,
+        }
+    }
+    if site.autoindex {
+        if let Ok((content_type, body)) = autoindex::listing(&file_path, uri_path, site, headers).await {
+            return Ok(Target::Listing(content_type, body));
+        }
+    }
+    Err(StatusCode::NOT_FOUND)
+}
+
+/// A file compressed ahead of time, served in place of the file it sits next
+/// to.
+pub struct Precompressed {
+    encoding: Encoding,
+    body: Body,
+    last_modified: Option<SystemTime>,
+}
+
+/// Looks for a sibling of `file_path` compressed in a coding the client
+/// accepts, e.g. `app.js.br` next to `app.js`. Symbolic links are not
+/// followed, so a sibling can not lead out of the website's resource.
+pub async fn precompressed(file_path: &Path, headers: &HeaderMap) -> Option<Precompressed> {
+    for encoding in compression::accepted(headers) {
+        let mut sibling = file_path.as_os_str().to_owned();
+        sibling.push(".");
+        sibling.push(encoding.extension());
+        match tokio::fs::symlink_metadata(&sibling).await {
+            Ok(metadata) if metadata.is_file() => {},
+            _ => continue,
+        }
+        if let Ok(file) = tokio::fs::File::open(&sibling).await {
+            if let Ok(metadata) = file.metadata().await {
+                return Some(Precompressed { encoding, body: Body::from_file(file, metadata.len()), last_modified: metadata.modified().ok() });
+            }
+        }
+    }
+    None
+}
+
+/// Finishes the 200 response for a file of the website. Validators,
+/// conditional requests, byte ranges and compression work the same way on
+/// every protocol.
+pub fn file_response(response: Builder, headers: &HeaderMap, site: &Website, content_type: &Mime, contents: Body, last_modified: Option<SystemTime>, precompressed: Option<Precompressed>) -> (Builder, Body) {
+    if let Some(precompressed) = precompressed {
+        return precompressed_response(response, headers, precompressed);
+    }
+    let len = contents.len().unwrap_or(0);
+    let encoding = compression::negotiate(headers, site, content_type, len);
+    let etag = match encoding {
+        Some(encoding) => compression::etag(&conditional::etag(len, last_modified), encoding),
+        None => conditional::etag(len, last_modified),
+    };
+    let mut response = response.header("ETag", &etag);
+    if compression::varies(site, content_type) {
+        response = response.header("Vary", "Accept-Encoding");
+    }
+    match conditional::evaluate(headers, &etag, last_modified) {
+        Some(StatusCode::NOT_MODIFIED) => (response.status(StatusCode::NOT_MODIFIED), Body::empty()),
+        Some(status) => (response.status(status).header("Content-Length", 0), Body::empty()),
+        None => match encoding {
+            Some(encoding) => (response.header("Content-Encoding", encoding.as_str()), compression::encode(contents, encoding, site.compression_level)),
+            None => {
+                let (response, contents) = ranges::apply(response, headers, contents, &etag, last_modified);
+                (response.header("Content-Length", contents.len().unwrap_or(0)), contents)
+            },
+        },
+    }
+}
+
+/// The precompressed file is a representation of its own, with its own size
+/// and validators, so ranges apply to the compressed bytes.
+fn precompressed_response(mut response: Builder, headers: &HeaderMap, precompressed: Precompressed) -> (Builder, Body) {
+    let Precompressed { encoding, body, last_modified } = precompressed;
+    if let (Some(response_headers), Some(last_modified)) = (response.headers_mut(), last_modified) {
+        response_headers.insert(LAST_MODIFIED, HeaderValue::from_str(&fmt_http_date(last_modified)).unwrap());
+    }
+    let etag = compression::etag(&conditional::etag(body.len().unwrap_or(0), last_modified), encoding);
+    let response = response.header("ETag", &etag).header("Vary", "Accept-Encoding");
+    match conditional::evaluate(headers, &etag, last_modified) {
+        Some(StatusCode::NOT_MODIFIED) => (response.status(StatusCode::NOT_MODIFIED), Body::empty()),
+        Some(status) => (response.status(status).header("Content-Length", 0), Body::empty()),
+        None => {
+            let (response, body) = ranges::apply(response.header("Content-Encoding", encoding.as_str()), headers, body, &etag, last_modified);
+            (response.header("Content-Length", body.len().unwrap_or(0)), body)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// A website whose root sits beside `outside.txt`, the file traversals
+    /// aim for.
+    fn website() -> Website {
+        let dir = testing::resource(&[("outside.txt", b"secret"), ("root/index.html", b"<h1>hi</h1>"), ("root/a/b.txt", b"b")]);
+        testing::website(&format!("resource = {:?}\n", format!("{}/root", dir)))
+    }
+
+    async fn status(site: &Website, target: &str) -> StatusCode {
+        let uri: Uri = target.parse().unwrap();
+        let (response, _) = serve(site, &uri, &HeaderMap::new(), "127.0.0.1:40000".parse().unwrap()).await;
+        response.body(()).unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn served_files_are_found() {
+        let site = website();
+        assert_eq!(status(&site, "/").await, StatusCode::OK);
+        assert_eq!(status(&site, "/a/b.txt").await, StatusCode::OK);
+        assert_eq!(status(&site, "/a/../a/./b.txt").await, StatusCode::OK);
+        assert_eq!(status(&site, "/missing.txt").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn dot_dot_traversal_is_forbidden() {
+        let site = website();
+        for target in ["/../outside.txt", "/a/../../outside.txt", "/%2e%2e/outside.txt", "/%2E%2E/outside.txt", "/.%2e/outside.txt", "/a/..%2f..%2foutside.txt", "/%2e%2e%2foutside.txt"] {
+            assert_eq!(status(&site, target).await, StatusCode::FORBIDDEN, "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn backslash_traversal_is_forbidden() {
+        let site = website();
+        for target in ["/..%5coutside.txt", "/a%5c..%5c..%5coutside.txt", "/%2e%2e%5coutside.txt", "/a/b.txt%5c..%5c..%5c..%5coutside.txt"] {
+            assert_eq!(status(&site, target).await, StatusCode::FORBIDDEN, "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn null_bytes_are_forbidden() {
+        let site = website();
+        for target in ["/index.html%00", "/index.html%00.txt", "/%00/../outside.txt", "/a%00/b.txt"] {
+            assert_eq!(status(&site, target).await, StatusCode::FORBIDDEN, "{}", target);
+        }
+    }
+
+    /// Overlong forms only ever come from clients hiding `.` or `/` from
+    /// filters, they are answered like the traversal they stand for, with a
+    /// 403 that `serve` logs as a security event.
+    #[tokio::test]
+    async fn overlong_utf8_is_forbidden() {
+        let site = website();
+        for target in ["/%c0%ae%c0%ae/outside.txt", "/%c0%2e%c0%2e/outside.txt", "/..%c0%afoutside.txt", "/..%c1%9coutside.txt", "/%e0%80%ae%e0%80%ae/outside.txt", "/%f0%80%80%ae./outside.txt"] {
+            assert_eq!(status(&site, target).await, StatusCode::FORBIDDEN, "{}", target);
+        }
+    }
+
+    #[tokio::test]
+    async fn other_invalid_utf8_is_a_bad_request() {
+        let site = website();
+        for target in ["/%ff", "/%e2%82", "/a%80b"] {
+            assert_eq!(status(&site, target).await, StatusCode::BAD_REQUEST, "{}", target);
+        }
+    }
+
+    #[test]
+    fn escapes_counts_backslashes_and_nulls() {
+        assert!(escapes("../a"));
+        assert!(escapes("a\\..\\..\\b"));
+        assert!(escapes("a/../../b"));
+        assert!(escapes("a\0b"));
+        assert!(!escapes("a/../b"));
+        assert!(!escapes("./a/./b/.."));
+    }
+}